@@ -0,0 +1,61 @@
+use avl_tree::AvlTree;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+/// Inserts a sorted batch of odd values between the even values of an existing tree.
+fn bulk_insert_sorted(c: &mut Criterion) {
+    let existing: AvlTree<u32> = (0..100_000).map(|value| value * 2).collect();
+    let batch: Vec<u32> = (0..10_000).map(|value| value * 20 + 1).collect();
+    let mut group = c.benchmark_group("bulk_insert_sorted");
+    group.bench_function("bulk_insert_sorted", |b| {
+        b.iter_batched(
+            || existing.clone(),
+            |mut tree| {
+                tree.bulk_insert_sorted(batch.iter().copied());
+                tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("repeated insert", |b| {
+        b.iter_batched(
+            || existing.clone(),
+            |mut tree| {
+                for value in &batch {
+                    tree.insert(*value);
+                }
+                tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Looks up every value of a tree in ascending order, where consecutive lookups share most of
+/// their path, and in a scattered order, where they do not.
+fn access_hint(c: &mut Criterion) {
+    let tree: AvlTree<u32> = (0..100_000).collect();
+    let ascending: Vec<u32> = (0..100_000).collect();
+    let scattered: Vec<u32> = (0..100_000).map(|value| value * 7919 % 100_000).collect();
+    let mut group = c.benchmark_group("access_hint");
+    for (name, lookups) in [("ascending", &ascending), ("scattered", &scattered)] {
+        group.bench_function(format!("access_hint {}", name), |b| {
+            b.iter(|| {
+                let mut hint = tree.access_hint();
+                lookups.iter().filter(|value| hint.contains(*value)).count()
+            })
+        });
+        group.bench_function(format!("get {}", name), |b| {
+            b.iter(|| {
+                lookups
+                    .iter()
+                    .filter(|value| tree.get(*value).is_some())
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bulk_insert_sorted, access_hint);
+criterion_main!(benches);
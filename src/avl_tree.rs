@@ -1,16 +1,64 @@
 #![allow(dead_code)]
 use graphviz_rust::dot_structures::*;
 use graphviz_rust::printer::{DotPrinter, PrinterContext};
-use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::borrow::Borrow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::default::Default;
-use std::fmt::Display;
+use std::fmt::{self, Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::iter::{FusedIterator, Peekable};
+use std::marker::PhantomData;
 use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::ptr::NonNull;
 
+/// Color presets for the dotfile representation of an AvlTree.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DotTheme {
+    /// Dark text on light nodes and a white background.
+    Light,
+    /// Light text on dark nodes and a dark background.
+    Dark,
+    /// Black outlines on white, suited for printing.
+    Print,
+}
+
+impl DotTheme {
+    /// Returns the graph, node and edge attribute statements of the theme.
+    fn statements(self) -> Vec<Stmt> {
+        let (background, fill, font, edge, font_name) = match self {
+            DotTheme::Light => ("#ffffff", "#dbe9f6", "#1b1b1b", "#5a5a5a", "Helvetica"),
+            DotTheme::Dark => ("#1e1e1e", "#3a3d41", "#f0f0f0", "#c5c5c5", "Helvetica"),
+            DotTheme::Print => ("#ffffff", "#ffffff", "#000000", "#000000", "Times-Roman"),
+        };
+        vec![
+            Stmt::GAttribute(GraphAttributes::Graph(vec![attribute(
+                "bgcolor", background,
+            )])),
+            Stmt::GAttribute(GraphAttributes::Node(vec![
+                attribute("style", "filled"),
+                attribute("fillcolor", fill),
+                attribute("color", edge),
+                attribute("fontcolor", font),
+                attribute("fontname", font_name),
+            ])),
+            Stmt::GAttribute(GraphAttributes::Edge(vec![attribute("color", edge)])),
+        ]
+    }
+}
+
+/// Builds a dot attribute with a quoted value.
+fn attribute(key: &str, value: &str) -> Attribute {
+    Attribute(
+        Id::Plain(String::from(key)),
+        Id::Escaped(format!("\"{}\"", value)),
+    )
+}
+
 /// Represents a single node in an avl tree
-#[derive(Debug, Clone, PartialEq)]
-pub struct AvlNode<T: Ord + Display> {
+#[derive(Clone, PartialEq)]
+pub struct AvlNode<T: Ord> {
     /// value stored in the node
     value: T,
     /// left subtree connected to this node
@@ -19,9 +67,11 @@ pub struct AvlNode<T: Ord + Display> {
     right: Link<T>,
     /// height of the node
     height: usize,
+    /// number of nodes in the subtree rooted at this node, including itself
+    size: usize,
 }
 
-impl<T: Ord + Display> AvlNode<T> {
+impl<T: Ord> AvlNode<T> {
     /// Retrieves the height of the left subtree if it exists, else returns 0.
     fn left_height(&self) -> usize {
         self.left
@@ -37,9 +87,20 @@ impl<T: Ord + Display> AvlNode<T> {
     }
 
     /// Updates the height of a node by setting it equal to 1 + the greater height of
-    /// its children.
+    /// its children. The subtree size is recomputed from the children alongside.
     fn update_height(&mut self) {
-        self.height = 1 + std::cmp::max(self.left_height(), self.right_height())
+        self.height = 1 + std::cmp::max(self.left_height(), self.right_height());
+        self.size = 1 + link_size(&self.left) + link_size(&self.right);
+    }
+
+    /// Checks in debug builds that the cached height matches the children, to catch heights
+    /// that drifted during an update while iterating over the tree.
+    fn debug_assert_height(&self) {
+        debug_assert_eq!(
+            self.height,
+            1 + std::cmp::max(self.left_height(), self.right_height()),
+            "cached height is out of date"
+        );
     }
 
     /// Computes the balance factor as defined for an [avl tree](https://en.wikipedia.org/wiki/AVL_tree#Definition).
@@ -116,8 +177,14 @@ impl<T: Ord + Display> AvlNode<T> {
         true
     }
 
-    /// Rebalances the current node to restore the avl critirium after an insertion.
+    /// Rebalances the current node to restore the avl critirium after an insertion or removal.
     fn rebalance(&mut self) -> bool {
+        self.rebalance_observed(&mut |_| {})
+    }
+
+    /// Rebalances the current node like [`AvlNode::rebalance`], reporting every performed
+    /// rotation to `on_rotate`.
+    fn rebalance_observed<F: FnMut(&Rotation)>(&mut self, on_rotate: &mut F) -> bool {
         match self.balance_factor() {
             -2 => {
                 // currently node is right-heavy
@@ -127,10 +194,12 @@ impl<T: Ord + Display> AvlNode<T> {
                     // inner node is currently left-heavy
                     if (*right_node.as_ptr()).balance_factor() == 1 {
                         (*right_node.as_ptr()).rotate_right();
+                        on_rotate(&Rotation::Right);
                     }
                 }
 
                 self.rotate_left();
+                on_rotate(&Rotation::Left);
                 true
             }
             2 => {
@@ -140,10 +209,12 @@ impl<T: Ord + Display> AvlNode<T> {
                     // inner node is currentyl right-heavy
                     if (*left_node.as_ptr()).balance_factor() == -1 {
                         (*left_node.as_ptr()).rotate_left();
+                        on_rotate(&Rotation::Left);
                     }
                 }
 
                 self.rotate_right();
+                on_rotate(&Rotation::Right);
 
                 true
             }
@@ -152,19 +223,334 @@ impl<T: Ord + Display> AvlNode<T> {
     }
 }
 
+/// A broken invariant reported by [`AvlTree::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation<'a, T> {
+    /// `value` follows `previous` in order, but is not greater than it.
+    Unordered { previous: &'a T, value: &'a T },
+    /// The subtrees of the node holding `value` differ in height by more than one.
+    Unbalanced {
+        value: &'a T,
+        left_height: usize,
+        right_height: usize,
+    },
+    /// The node holding `value` caches a height that does not match its children.
+    StaleHeight {
+        value: &'a T,
+        cached: usize,
+        actual: usize,
+    },
+    /// The node holding `value` caches a subtree size that does not match its children.
+    StaleSize {
+        value: &'a T,
+        cached: usize,
+        actual: usize,
+    },
+}
+
+impl<T: Display> Display for Violation<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Unordered { previous, value } => {
+                write!(f, "{} follows {} but is not greater", value, previous)
+            }
+            Violation::Unbalanced {
+                value,
+                left_height,
+                right_height,
+            } => write!(
+                f,
+                "node {} is unbalanced with subtree heights {} and {}",
+                value, left_height, right_height
+            ),
+            Violation::StaleHeight {
+                value,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "node {} caches height {} but has height {}",
+                value, cached, actual
+            ),
+            Violation::StaleSize {
+                value,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "node {} caches size {} but has size {}",
+                value, cached, actual
+            ),
+        }
+    }
+}
+
+/// Reason for rejecting a level-order array in [`AvlTree::from_level_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelOrderError {
+    /// The values do not increase from left to right.
+    Unordered,
+    /// The subtrees of a node differ in height by more than one.
+    Unbalanced,
+    /// Entries are left over after the children of every node have been read.
+    TrailingEntries,
+}
+
+impl Display for LevelOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelOrderError::Unordered => {
+                f.write_str("level order does not describe an ordered tree")
+            }
+            LevelOrderError::Unbalanced => {
+                f.write_str("level order does not describe a balanced tree")
+            }
+            LevelOrderError::TrailingEntries => {
+                f.write_str("level order has entries without a parent")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LevelOrderError {}
+
+/// Panics with a description of the first broken invariant if `tree` is not a valid AvlTree,
+/// see [`AvlTree::validate`]. Meant to be called from tests of code embedding an AvlTree.
+#[cfg(any(test, feature = "test-util"))]
+pub fn debug_assert_tree<T: Ord + Debug>(tree: &AvlTree<T>) {
+    if let Err(violation) = tree.validate() {
+        panic!("invalid AvlTree: {:?}", violation);
+    }
+}
+
+/// Which side of a node holds the higher subtree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Balance {
+    /// The left subtree is higher than the right one.
+    LeftHeavy,
+    /// Both subtrees have the same height.
+    Balanced,
+    /// The right subtree is higher than the left one.
+    RightHeavy,
+}
+
+/// A single rotation performed while rebalancing the tree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rotation {
+    /// The right child of a node became its parent.
+    Left,
+    /// The left child of a node became its parent.
+    Right,
+}
+
+/// Query types that can be compared against values of type `T` stored in an AvlTree.
+///
+/// Every `Q: Ord` that `T` can be borrowed as implements this trait, e.g. `str` for `String`.
+/// For cases without a `Borrow` relationship, such as looking up a record by a bare id,
+/// implement it for the query type directly. The ordering must be consistent with `T: Ord`.
+pub trait Comparable<T: ?Sized> {
+    /// Compares the query `self` against the stored value `key`.
+    fn compare(&self, key: &T) -> Ordering;
+}
+
+impl<Q: Ord + ?Sized, T: Borrow<Q> + ?Sized> Comparable<T> for Q {
+    fn compare(&self, key: &T) -> Ordering {
+        self.cmp(key.borrow())
+    }
+}
+
 /// A link between nodes in a tree.
 type Link<T> = Option<NonNull<AvlNode<T>>>;
 
+/// Callback registered with [`AvlTree::set_observer`].
+pub type Observer<T> = Box<dyn FnMut(&Event<'_, T>)>;
+
+/// Callback registered with [`AvlTree::enable_self_check`].
+pub type SelfCheckCallback<T> = Box<dyn FnMut(&Violation<'_, T>)>;
+
+/// Receives a broken invariant found by a [`SelfCheck`] and the log of the checked operations.
+type ViolationHandler<T> = Box<dyn FnMut(&Violation<'_, T>, &[String])>;
+
+/// Invariant checks run after every single-value insertion and removal, see
+/// [`AvlTree::with_strict_checks`].
+struct SelfCheck<T> {
+    /// receives each broken invariant together with the log of the checked operations
+    on_violation: ViolationHandler<T>,
+    /// describes a value for the operation log, no log is kept without it
+    describe: Option<fn(&T) -> String>,
+    log: Vec<String>,
+}
+
+/// Retrieves the height of the subtree behind `link`, which is 0 for an empty link.
+fn link_height<T: Ord>(link: &Link<T>) -> usize {
+    link.as_ref()
+        .map_or(0, |node| unsafe { (*node.as_ptr()).height })
+}
+
+/// Retrieves the number of nodes in the subtree behind `link`, which is 0 for an empty link.
+fn link_size<T: Ord>(link: &Link<T>) -> usize {
+    link.as_ref()
+        .map_or(0, |node| unsafe { (*node.as_ptr()).size })
+}
+
+/// Frees every node of the subtree behind `link`.
+fn free_subtree<T: Ord>(link: Link<T>) {
+    if link.is_none() {
+        return;
+    }
+    let mut stack = Vec::new();
+    let mut curr_node = link.unwrap();
+    let mut nodes = Vec::new();
+
+    stack.push(curr_node);
+    while !stack.is_empty() {
+        curr_node = stack.pop().unwrap();
+        nodes.push(curr_node);
+        unsafe {
+            if (*curr_node.as_ptr()).right.is_some() {
+                stack.push((*curr_node.as_ptr()).right.unwrap());
+            }
+            if (*curr_node.as_ptr()).left.is_some() {
+                stack.push((*curr_node.as_ptr()).left.unwrap());
+            }
+        }
+    }
+
+    for node in nodes {
+        unsafe {
+            let _box = Box::from_raw(node.as_ptr());
+        }
+    }
+}
+
 /// Generic AvlTree implementation that permits no duplicate entries.
-#[derive(Debug, Clone, PartialEq)]
-pub struct AvlTree<T: Ord + Display> {
+pub struct AvlTree<T: Ord> {
     root: Link<T>,
+    /// callback notified about insertions and removals, see [`AvlTree::set_observer`]
+    observer: Option<Observer<T>>,
+    /// invariant checks run after each insertion and removal, if enabled
+    self_check: Option<SelfCheck<T>>,
+    /// balance quality above which a removal rebuilds the tree, see [`AvlTree::set_auto_rebuild`]
+    auto_rebuild: Option<f64>,
 }
 
-impl<T: Ord + Display> AvlTree<T> {
+/// A change to an AvlTree reported to its observer, see [`AvlTree::set_observer`].
+#[derive(Debug, PartialEq)]
+pub enum Event<'a, T> {
+    /// The value was inserted into the tree.
+    Inserted(&'a T),
+    /// The value was removed from the tree.
+    Removed(T),
+}
+
+impl<T: Ord> AvlTree<T> {
+    /// An empty AvlTree, usable in const contexts such as array initializers like
+    /// `[AvlTree::EMPTY; 4]`.
+    pub const EMPTY: Self = Self::from_root(None);
+
     /// Create a new AvlTree instance
-    pub fn new() -> Self {
-        Self { root: None }
+    pub const fn new() -> Self {
+        Self::EMPTY
+    }
+
+    /// Wraps the subtree behind `root` into an AvlTree without an observer or self check.
+    const fn from_root(root: Link<T>) -> Self {
+        Self {
+            root,
+            observer: None,
+            self_check: None,
+            auto_rebuild: None,
+        }
+    }
+
+    /// Registers a callback that is notified about every value inserted into or removed from
+    /// the AvlTree, e.g. to keep a derived structure in sync. Only successful single-value
+    /// operations like [`AvlTree::insert`] and [`AvlTree::take`] are reported, bulk operations
+    /// that rebuild the tree are not. A previously registered observer is replaced. Clones of
+    /// the AvlTree start without an observer.
+    ///
+    /// ## Arguments
+    /// * `observer` The callback receiving each [`Event`] after the tree changed
+    pub fn set_observer(&mut self, observer: Observer<T>) {
+        self.observer = Some(observer);
+    }
+
+    /// Validates this AvlTree after every subsequent insertion and removal and reports each
+    /// broken invariant to `callback` instead of panicking, to investigate a single suspicious
+    /// tree without checking all of them. Each check walks the whole tree, making every
+    /// operation O(n). Bulk operations are not checked and clones start without checks. A
+    /// previously enabled self check is replaced.
+    ///
+    /// ## Arguments
+    /// * `callback` The callback receiving the first broken invariant after each operation
+    pub fn enable_self_check(&mut self, mut callback: SelfCheckCallback<T>)
+    where
+        T: 'static,
+    {
+        self.self_check = Some(SelfCheck {
+            on_violation: Box::new(move |violation, _| callback(violation)),
+            describe: None,
+            log: Vec::new(),
+        });
+    }
+
+    /// Stops the checks started by [`AvlTree::enable_self_check`] or
+    /// [`AvlTree::with_strict_checks`].
+    pub fn disable_self_check(&mut self) {
+        self.self_check = None;
+    }
+
+    /// Rebuilds the AvlTree with [`AvlTree::rebuild`] whenever a removal leaves it with a
+    /// [`AvlTree::balance_quality`] above `threshold`, e.g. `1.5` for a height of more than one
+    /// and a half times the optimal one. Each rebuild takes O(n), so thresholds close to `1.0`
+    /// rebuild on most removals. Clones start without the policy.
+    ///
+    /// ## Arguments
+    /// * `threshold` The balance quality a removal may leave behind without a rebuild
+    pub fn set_auto_rebuild(&mut self, threshold: f64) {
+        self.auto_rebuild = Some(threshold);
+    }
+
+    /// Reports the removal of `value` to the observer and the self check and hands it back.
+    fn notify_removed(&mut self, value: T) -> T {
+        self.log_operation("remove", &value);
+        if self
+            .auto_rebuild
+            .is_some_and(|threshold| self.balance_quality() > threshold)
+        {
+            self.rebuild();
+        }
+        self.run_self_check();
+        match self.observer.as_mut() {
+            None => value,
+            Some(observer) => {
+                let event = Event::Removed(value);
+                observer(&event);
+                let Event::Removed(value) = event else {
+                    unreachable!()
+                };
+                value
+            }
+        }
+    }
+
+    /// Appends `operation` on `value` to the log of the self check, if one is kept.
+    fn log_operation(&mut self, operation: &str, value: &T) {
+        if let Some(check) = self.self_check.as_mut() {
+            if let Some(describe) = check.describe {
+                check.log.push(format!("{} {}", operation, describe(value)));
+            }
+        }
+    }
+
+    /// Validates the tree if a self check is enabled and reports the first broken invariant.
+    fn run_self_check(&mut self) {
+        if let Some(mut check) = self.self_check.take() {
+            if let Err(violation) = self.validate() {
+                (check.on_violation)(&violation, &check.log);
+            }
+            self.self_check = Some(check);
+        }
     }
 
     /// Try to insert the value into the tree. Returns true on success, else false.
@@ -172,6 +558,54 @@ impl<T: Ord + Display> AvlTree<T> {
     /// ## Arguments
     /// * `value` - Value to insert into the tree
     pub fn insert(&mut self, value: T) -> bool {
+        self.insert_observed(value, |_| {})
+    }
+
+    /// Try to insert the value into the tree like [`AvlTree::insert`], calling `on_rotate` for
+    /// every rotation performed while rebalancing. Returns true on success, else false.
+    ///
+    /// ## Arguments
+    /// * `value` - Value to insert into the tree
+    /// * `on_rotate` - Callback receiving each rotation in the order they are performed
+    pub fn insert_observed<F: FnMut(&Rotation)>(&mut self, value: T, mut on_rotate: F) -> bool {
+        self.insert_or_return_observed(value, &mut on_rotate, true)
+            .is_none()
+    }
+
+    /// Inserts the value like into a plain binary search tree, without any rotations, so the
+    /// AvlTree can end up unbalanced, e.g. to show students what the rotations of
+    /// [`AvlTree::insert`] prevent. Heights and sizes are kept up to date and lookups keep
+    /// working, but they lose their O(log n) bound and [`AvlTree::validate`] reports the
+    /// unbalanced nodes. Returns true on success, else false.
+    ///
+    /// ## Arguments
+    /// * `value` - Value to insert into the tree
+    pub fn insert_without_rebalancing(&mut self, value: T) -> bool {
+        self.insert_or_return_observed(value, &mut |_| {}, false)
+            .is_none()
+    }
+
+    /// Try to insert the value into the tree without ever replacing an existing element.
+    /// Unlike [`AvlTree::insert`], a rejected value is handed back to the caller instead of
+    /// being dropped.
+    ///
+    /// ## Arguments
+    /// * `value` - Value to insert into the tree
+    /// ## Returns
+    /// `None` if `value` was inserted, else `Some(value)` unchanged.
+    pub fn insert_or_return(&mut self, value: T) -> Option<T> {
+        self.insert_or_return_observed(value, &mut |_| {}, true)
+    }
+
+    /// Inserts `value` and, if `rebalance` is set, rebalances the path back to the root,
+    /// reporting each rotation to `on_rotate`. Returns `value` if an equal element is already
+    /// present.
+    fn insert_or_return_observed<F: FnMut(&Rotation)>(
+        &mut self,
+        value: T,
+        on_rotate: &mut F,
+        rebalance: bool,
+    ) -> Option<T> {
         let mut current_tree = &mut self.root;
         let mut prev_ptrs = Vec::<*mut AvlNode<T>>::new();
         unsafe {
@@ -179,343 +613,5373 @@ impl<T: Ord + Display> AvlTree<T> {
                 prev_ptrs.push(current_node.as_ptr());
                 match (*current_node.as_ptr()).value.cmp(&value) {
                     Ordering::Greater => current_tree = &mut (*current_node.as_ptr()).left,
-                    Ordering::Equal => return false,
+                    Ordering::Equal => return Some(value),
                     Ordering::Less => current_tree = &mut (*current_node.as_ptr()).right,
                 }
             }
         }
         unsafe {
-            *current_tree = Some(NonNull::new_unchecked(Box::into_raw(Box::new(AvlNode {
+            let node = Box::into_raw(Box::new(AvlNode {
                 value,
                 left: None,
                 right: None,
                 height: 1,
-            }))));
+                size: 1,
+            }));
+            *current_tree = Some(NonNull::new_unchecked(node));
+            // reported before rebalancing, as rotations move the value to another node
+            if let Some(observer) = self.observer.as_mut() {
+                observer(&Event::Inserted(&(*node).value));
+            }
+            self.log_operation("insert", &(*node).value);
         }
 
         for ptr in prev_ptrs.into_iter().rev() {
             unsafe {
                 let node = &mut *ptr;
                 node.update_height();
-                node.rebalance();
+                if rebalance {
+                    node.rebalance_observed(on_rotate);
+                }
             }
         }
+        self.run_self_check();
 
-        true
+        None
+    }
+
+    /// Removes the value from the tree. Returns true if it was present, else false.
+    ///
+    /// ## Arguments
+    /// * `value` The value to remove, either a `T` or any query type comparable against `T`
+    pub fn remove<Q: ?Sized + Comparable<T>>(&mut self, value: &Q) -> bool {
+        self.take(value).is_some()
     }
 
-    /// Checks if the AvlTree contains the value T.
+    /// Removes the value from the tree and hands the stored element back to the caller.
+    /// A node with two children takes over the value of its in-order successor, whose node
+    /// is unlinked instead, but the returned element is always the one matching `value`.
     ///
     /// ## Arguments
-    /// * `value` The value to check
+    /// * `value` The value to remove, either a `T` or any query type comparable against `T`
     /// ## Returns
-    /// `true`, when `value` is in the AvlTree, else `false``.
-    pub fn contains(&self, value: &T) -> bool {
-        let mut current_tree = &self.root;
-        while let Some(node) = current_tree {
-            unsafe {
-                match (*node.as_ptr()).value.cmp(value) {
-                    Ordering::Greater => current_tree = &(*node.as_ptr()).left,
-                    Ordering::Equal => return true,
-                    Ordering::Less => current_tree = &(*node.as_ptr()).right,
+    /// `Some(stored)` if the value was present, else `None`.
+    pub fn take<Q: ?Sized + Comparable<T>>(&mut self, value: &Q) -> Option<T> {
+        let mut current_tree: *mut Link<T> = &mut self.root;
+        let mut prev_ptrs = Vec::<*mut AvlNode<T>>::new();
+        unsafe {
+            loop {
+                let node = (*current_tree)?;
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &mut (*node.as_ptr()).left,
+                    Ordering::Equal => break,
+                    Ordering::Greater => current_tree = &mut (*node.as_ptr()).right,
                 }
+                prev_ptrs.push(node.as_ptr());
             }
+            let removed = Self::unlink(current_tree, prev_ptrs);
+            Some(self.notify_removed(removed))
         }
-        false
     }
 
-    /// Return the number of elements in the AvlTree.
-    pub fn len(&self) -> usize {
-        self.iter().count()
+    /// Removes the value stored in the root node and returns it, whatever its position in
+    /// sorted order. Like any other removal, the root takes over the value of its in-order
+    /// successor if it has two children, and the tree is rebalanced afterwards.
+    pub fn remove_root(&mut self) -> Option<T> {
+        self.root?;
+        let removed = unsafe { Self::unlink(&mut self.root, Vec::new()) };
+        Some(self.notify_removed(removed))
     }
 
-    /// Return a graphviz dotfile representation of the AvlTree.
-    pub fn as_dotfile(&self) -> Option<String> {
-        if self.root.is_some() {
-            unsafe {
-                let mut graph = Graph::DiGraph {
-                    id: Id::Plain(String::from("AVL_Tree")),
-                    strict: true,
-                    stmts: Vec::new(),
-                };
-                let mut queue = VecDeque::new();
-                queue.push_back(self.root.unwrap());
-                while !queue.is_empty() {
-                    let node = queue.pop_front().unwrap();
-                    graph.add_stmt(Stmt::Node(Node::new(
-                        NodeId {
-                            0: Id::Plain((*node.as_ptr()).value.to_string()),
-                            1: None,
-                        },
-                        Vec::new(),
-                    )));
-                    if (*node.as_ptr()).left.is_some() {
-                        queue.push_back((*node.as_ptr()).left.unwrap());
-                        graph.add_stmt(Stmt::Edge(Edge {
-                            ty: EdgeTy::Pair(
-                                Vertex::N(NodeId {
-                                    0: Id::Plain((*node.as_ptr()).value.to_string()),
-                                    1: None,
-                                }),
-                                Vertex::N(NodeId {
-                                    0: Id::Plain(
-                                        (*(*node.as_ptr()).left.unwrap().as_ptr())
-                                            .value
-                                            .to_string(),
-                                    ),
-                                    1: None,
-                                }),
-                            ),
-                            attributes: Vec::new(),
-                        }));
-                    }
-                    if (*node.as_ptr()).right.is_some() {
-                        queue.push_back((*node.as_ptr()).right.unwrap());
-                        graph.add_stmt(Stmt::Edge(Edge {
-                            ty: EdgeTy::Pair(
-                                Vertex::N(NodeId {
-                                    0: Id::Plain((*node.as_ptr()).value.to_string()),
-                                    1: None,
-                                }),
-                                Vertex::N(NodeId {
-                                    0: Id::Plain(
-                                        (*(*node.as_ptr()).right.unwrap().as_ptr())
-                                            .value
-                                            .to_string(),
-                                    ),
-                                    1: None,
-                                }),
-                            ),
-                            attributes: Vec::new(),
-                        }));
+    /// Frees the node behind the non-empty link `current_tree` and returns its value, then
+    /// rebalances the ancestors in `prev_ptrs`, given from the root downwards. A node with
+    /// two children takes over the value of its in-order successor, whose node is freed
+    /// instead.
+    ///
+    /// ## Safety
+    /// `current_tree` must point to a non-empty link in the tree and `prev_ptrs` must hold
+    /// exactly the nodes on the path from the root to that link.
+    unsafe fn unlink(current_tree: *mut Link<T>, mut prev_ptrs: Vec<*mut AvlNode<T>>) -> T {
+        let node = (*current_tree).unwrap();
+        let removed = match ((*node.as_ptr()).left, (*node.as_ptr()).right) {
+            (None, child) | (child, None) => {
+                *current_tree = child;
+                Box::from_raw(node.as_ptr()).value
+            }
+            (Some(_), Some(_)) => {
+                prev_ptrs.push(node.as_ptr());
+                let mut successor_tree: *mut Link<T> = &mut (*node.as_ptr()).right;
+                let successor = loop {
+                    let successor = (*successor_tree).unwrap();
+                    if (*successor.as_ptr()).left.is_none() {
+                        break successor;
                     }
-                }
-                Some(graph.print(&mut PrinterContext::default()))
+                    prev_ptrs.push(successor.as_ptr());
+                    successor_tree = &mut (*successor.as_ptr()).left;
+                };
+                *successor_tree = (*successor.as_ptr()).right;
+                let successor = Box::from_raw(successor.as_ptr());
+                mem::replace(&mut (*node.as_ptr()).value, successor.value)
             }
-        } else {
-            None
+        };
+
+        // rotations keep every node at the root of its subtree, so the path stays valid
+        for ptr in prev_ptrs.into_iter().rev() {
+            let node = &mut *ptr;
+            node.update_height();
+            node.rebalance();
         }
-    }
-}
 
-impl<'a, T: Ord + Display + 'a> AvlTree<T> {
-    /// Returns an iterator over the borrowed values in the tree.
-    /// The iterator performs an in-order depth traversal of the tree.
-    pub fn iter(&'a self) -> impl Iterator<Item = &'a T> + 'a {
-        self.node_iter().map(|node| &node.value)
+        removed
     }
 
-    /// Returns an iterator over the actual nodes in the tree.
-    /// The iterator performs an in-order depth traversal of the tree.
-    pub fn node_iter(&'a self) -> impl Iterator<Item = &'a AvlNode<T>> + 'a {
-        NodeIter {
-            prev_nodes: Vec::new(),
-            current_tree: &self.root,
+    /// Fills an empty tree from `iter` by sorting the values and building the tree bottom
+    /// up in O(n log n), which avoids the rotations of inserting them one by one. Like
+    /// repeated inserts, the first of several equal values is kept. The fast path is only
+    /// taken if no observer is set, as it would not be notified of the insertions.
+    ///
+    /// ## Returns
+    /// `(inserted, duplicates)` like [`AvlTree::extend_counted`], or `iter` untouched if the
+    /// tree is not empty or has an observer.
+    fn build_if_empty<I: Iterator<Item = T>>(&mut self, iter: I) -> Result<(usize, usize), I> {
+        if !self.is_empty() || self.observer.is_some() {
+            return Err(iter);
         }
+        let mut values: Vec<T> = iter.collect();
+        let total = values.len();
+        // the sort is stable, so dedup keeps the first of equal values
+        values.sort();
+        values.dedup();
+        let inserted = values.len();
+        self.root = Self::build_sorted(&mut values.into_iter(), inserted);
+        Ok((inserted, total - inserted))
     }
-}
 
-impl<T: Ord + Display> Drop for AvlTree<T> {
-    fn drop(&mut self) {
-        if self.root.is_none() {
-            return;
+    /// Inserts every value of `iter` into the tree and reports how many were new. An empty
+    /// tree is built from the sorted values at once instead, which is faster than inserting
+    /// them one by one but yields a different shape than [`Extend`] does.
+    ///
+    /// ## Arguments
+    /// * `iter` Values to insert into the tree
+    /// ## Returns
+    /// `(inserted, duplicates)`, where `duplicates` counts the values that were already present.
+    pub fn extend_counted<I: IntoIterator<Item = T>>(&mut self, iter: I) -> (usize, usize) {
+        let iter = match self.build_if_empty(iter.into_iter()) {
+            Ok(counts) => return counts,
+            Err(iter) => iter,
+        };
+        let mut inserted = 0;
+        let mut duplicates = 0;
+        for value in iter {
+            if self.insert(value) {
+                inserted += 1;
+            } else {
+                duplicates += 1;
+            }
         }
-        let mut stack = Vec::new();
-        let mut curr_node = self.root.unwrap();
-        let mut nodes = Vec::new();
+        (inserted, duplicates)
+    }
+
+    /// Checks if the AvlTree contains the value T. Like with `BTreeSet`, any type `T` can be
+    /// borrowed as works for the query, e.g. a `&str` for an `AvlTree<String>`, so no `T` has
+    /// to be allocated just to look it up.
+    ///
+    /// ## Arguments
+    /// * `value` The value to check, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// `true`, when `value` is in the AvlTree, else `false``.
+    pub fn contains<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> bool {
+        self.find_node(value).is_some()
+    }
+
+    /// Looks up `value` and returns a reference to the stored element. This matters when the
+    /// ordering of `T` only considers part of it, e.g. an id, and the rest of the stored
+    /// element is of interest.
+    ///
+    /// ## Arguments
+    /// * `value` The value to look up, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// `Some(stored)`, when `value` is in the AvlTree, else `None`.
+    pub fn get<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<&T> {
+        self.find_node(value)
+            .map(|node| unsafe { &(*node.as_ptr()).value })
+    }
 
-        stack.push(curr_node);
-        while !stack.is_empty() {
-            curr_node = stack.pop().unwrap();
-            nodes.push(curr_node);
+    /// Looks up `value` like [`AvlTree::get`] and also counts the comparisons made on the way,
+    /// which is the length of the search path. Paths much longer than expected for the size
+    /// of the tree hint at a degraded structure worth rebuilding.
+    ///
+    /// ## Arguments
+    /// * `value` The value to look up, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// The stored element, if any, and the number of comparisons, which is at most `height()`.
+    pub fn get_instrumented<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> (Option<&T>, usize) {
+        let mut comparisons = 0;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            comparisons += 1;
             unsafe {
-                if (*curr_node.as_ptr()).right.is_some() {
-                    stack.push((*curr_node.as_ptr()).right.unwrap());
-                }
-                if (*curr_node.as_ptr()).left.is_some() {
-                    stack.push((*curr_node.as_ptr()).left.unwrap());
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return (Some(&(*node.as_ptr()).value), comparisons),
+                    Ordering::Greater => current_tree = &(*node.as_ptr()).right,
                 }
             }
         }
+        (None, comparisons)
+    }
+
+    /// Looks up `value` and returns the stored element as both key and value. Since the
+    /// AvlTree is a set, both references point to the same element. This mirrors
+    /// `BTreeMap::get_key_value` to ease porting map-based code.
+    ///
+    /// ## Arguments
+    /// * `value` The value to look up, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// `Some((stored, stored))`, when `value` is in the AvlTree, else `None`.
+    pub fn get_key_value<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<(&T, &T)> {
+        self.get(value).map(|stored| (stored, stored))
+    }
 
-        for node in nodes {
+    /// Descends the tree and returns the node matching `value`, if any.
+    fn find_node<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Link<T> {
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
             unsafe {
-                let _box = Box::from_raw(node.as_ptr());
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return Some(*node),
+                    Ordering::Greater => current_tree = &(*node.as_ptr()).right,
+                }
             }
         }
+        None
     }
-}
 
-impl<T: Ord + Display> Default for AvlTree<T> {
-    fn default() -> Self {
-        Self { root: None }
+    /// Checks the in-order sequence for adjacent equal values. A correctly maintained tree
+    /// never contains duplicates, so this only reports `true` if the invariant was broken,
+    /// e.g. by mutating values through [`AvlTree::node_iter_mut`].
+    pub fn has_duplicates(&self) -> bool {
+        self.iter()
+            .zip(self.iter().skip(1))
+            .any(|(prev, next)| prev == next)
     }
-}
-
-pub struct Iter<'a, T: Ord + Display> {
-    prev_nodes: Vec<&'a AvlNode<T>>,
-    current_tree: &'a Link<T>,
-}
 
-pub struct NodeIter<'a, T: Ord + Display> {
-    prev_nodes: Vec<&'a AvlNode<T>>,
-    current_tree: &'a Link<T>,
-}
+    /// Checks the invariants of the AvlTree: every value is greater than the one preceding it,
+    /// the subtrees of every node differ in height by at most one and the cached heights and
+    /// subtree sizes match the children. A tree maintained through the AvlTree api always
+    /// passes, so this is meant for tests and debugging.
+    ///
+    /// ## Returns
+    /// `Ok(())` if the tree is valid, else the first [`Violation`] found.
+    pub fn validate(&self) -> Result<(), Violation<'_, T>> {
+        // walked by hand, as the iterators assert the very heights that are checked here
+        let mut pending: Vec<&AvlNode<T>> = Vec::new();
+        pending.extend(self.root.map(|root| unsafe { &*root.as_ptr() }));
+        while let Some(node) = pending.pop() {
+            pending.extend(
+                [node.left, node.right]
+                    .into_iter()
+                    .flatten()
+                    .map(|child| unsafe { &*child.as_ptr() }),
+            );
+            let (left_height, right_height) = (node.left_height(), node.right_height());
+            let height = 1 + std::cmp::max(left_height, right_height);
+            let size = 1 + link_size(&node.left) + link_size(&node.right);
+            if node.height != height {
+                return Err(Violation::StaleHeight {
+                    value: &node.value,
+                    cached: node.height,
+                    actual: height,
+                });
+            }
+            if node.size != size {
+                return Err(Violation::StaleSize {
+                    value: &node.value,
+                    cached: node.size,
+                    actual: size,
+                });
+            }
+            if left_height.abs_diff(right_height) > 1 {
+                return Err(Violation::Unbalanced {
+                    value: &node.value,
+                    left_height,
+                    right_height,
+                });
+            }
+        }
+        match self
+            .iter()
+            .zip(self.iter().skip(1))
+            .find(|(prev, next)| prev >= next)
+        {
+            Some((previous, value)) => Err(Violation::Unordered { previous, value }),
+            None => Ok(()),
+        }
+    }
 
-impl<'a, T: Ord + Display + 'a> Iterator for NodeIter<'a, T> {
-    type Item = &'a AvlNode<T>;
+    /// Reports whether the node holding `value` leans to one side.
+    ///
+    /// ## Arguments
+    /// * `value` The value whose node to inspect
+    /// ## Returns
+    /// The [`Balance`] of the node, or `None` if `value` is not in the AvlTree.
+    pub fn balance_state<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<Balance> {
+        self.find_node(value).map(|node| {
+            match unsafe { (*node.as_ptr()).balance_factor() }.cmp(&0) {
+                Ordering::Greater => Balance::LeftHeavy,
+                Ordering::Equal => Balance::Balanced,
+                Ordering::Less => Balance::RightHeavy,
+            }
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match *self.current_tree {
-                None => match self.prev_nodes.pop() {
-                    None => return None,
-                    Some(ref prev_node) => {
-                        self.current_tree = &prev_node.right;
-                        return Some(&prev_node);
+    /// Estimates the number of values smaller than `value` in O(height) from the cached
+    /// heights alone, without counting nodes.
+    ///
+    /// Every left subtree skipped during the descent is known to hold between the minimum
+    /// number of nodes of an AVL tree of its height and `2^height - 1` nodes. The estimate is
+    /// the middle of the summed bounds, so it is off by at most half their difference. For a
+    /// subtree of height `h`, the minimum is about `0.72 * 1.618^h`, so the estimate can be
+    /// off by roughly a third of the tree in the worst case. Use [`AvlTree::rank`] for the
+    /// exact rank.
+    ///
+    /// ## Arguments
+    /// * `value` The value whose rank to estimate, which need not be in the AvlTree
+    pub fn estimate_rank(&self, value: &T) -> usize {
+        let (lower, upper) = self.rank_bounds(value);
+        lower + (upper - lower) / 2
+    }
+
+    /// Computes lower and upper bounds of the rank of `value` from the subtree heights.
+    fn rank_bounds(&self, value: &T) -> (usize, usize) {
+        /// Smallest number of nodes in an AVL tree of the given height.
+        fn min_nodes(height: usize) -> usize {
+            let (mut prev, mut current) = (0usize, 1usize);
+            if height == 0 {
+                return 0;
+            }
+            for _ in 1..height {
+                (prev, current) = (current, current.saturating_add(prev).saturating_add(1));
+            }
+            current
+        }
+
+        /// Largest number of nodes in an AVL tree of the given height.
+        fn max_nodes(height: usize) -> usize {
+            2usize.saturating_pow(height as u32) - 1
+        }
+
+        let (mut lower, mut upper) = (0, 0);
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                let left_height = (*node.as_ptr()).left_height();
+                match (*node.as_ptr()).value.cmp(value) {
+                    Ordering::Greater => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => {
+                        lower += min_nodes(left_height);
+                        upper += max_nodes(left_height);
+                        break;
+                    }
+                    Ordering::Less => {
+                        lower += min_nodes(left_height) + 1;
+                        upper += max_nodes(left_height) + 1;
+                        current_tree = &(*node.as_ptr()).right;
+                    }
+                }
+            }
+        }
+        (lower, upper)
+    }
+
+    /// Retrieves the `k`-th smallest value, counting from 0, in O(height) by descending along
+    /// the cached subtree sizes.
+    ///
+    /// ## Arguments
+    /// * `k` The zero-based position of the value in sorted order
+    /// ## Returns
+    /// The value at position `k`, or `None` if `k >= len()`.
+    pub fn select(&self, mut k: usize) -> Option<&T> {
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                let left_size = link_size(&(*node.as_ptr()).left);
+                match k.cmp(&left_size) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return Some(&(*node.as_ptr()).value),
+                    Ordering::Greater => {
+                        k -= left_size + 1;
+                        current_tree = &(*node.as_ptr()).right;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Picks a value uniformly at random in O(height), by drawing a random position and
+    /// retrieving the value there with [`AvlTree::select`].
+    ///
+    /// ## Arguments
+    /// * `rng` The random number generator to draw the position from
+    /// ## Returns
+    /// A random value, or `None` if the tree is empty.
+    #[cfg(feature = "rand")]
+    pub fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.select(rng.gen_range(0..self.len()))
+    }
+
+    /// Counts the values strictly less than `value` in O(height) from the cached subtree
+    /// sizes. `value` need not be in the AvlTree, and `rank(select(k)) == k` for every stored
+    /// position `k`. Use [`AvlTree::checked_rank`] to get `None` for absent values instead.
+    ///
+    /// ## Arguments
+    /// * `value` The value to rank, either a `T` or any query type comparable against `T`
+    pub fn rank<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> usize {
+        let mut rank = 0;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return rank + link_size(&(*node.as_ptr()).left),
+                    Ordering::Greater => {
+                        rank += link_size(&(*node.as_ptr()).left) + 1;
+                        current_tree = &(*node.as_ptr()).right;
+                    }
+                }
+            }
+        }
+        rank
+    }
+
+    /// Retrieves the zero-based position of a stored value in sorted order in O(height).
+    /// Unlike [`AvlTree::rank`], which counts the smaller values of any query, this tells
+    /// absent values apart, so `checked_rank(v) == Some(k)` exactly if `select(k) == Some(v)`.
+    ///
+    /// ## Arguments
+    /// * `value` The value to look up, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// The position of `value`, or `None` if it is not in the AvlTree.
+    pub fn checked_rank<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<usize> {
+        let mut rank = 0;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return Some(rank + link_size(&(*node.as_ptr()).left)),
+                    Ordering::Greater => {
+                        rank += link_size(&(*node.as_ptr()).left) + 1;
+                        current_tree = &(*node.as_ptr()).right;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Return the number of elements in the AvlTree. Every node tracks the size of its
+    /// subtree, so this runs in O(1).
+    pub fn len(&self) -> usize {
+        link_size(&self.root)
+    }
+
+    /// Removes all values from the AvlTree and frees every node, keeping the tree itself and
+    /// its observer for reuse. Removed values are not reported to the observer.
+    pub fn clear(&mut self) {
+        free_subtree(self.root.take());
+    }
+
+    /// Returns the number of levels of the AvlTree, which is 0 for an empty tree and 1 for a
+    /// tree holding a single value.
+    pub fn height(&self) -> usize {
+        link_height(&self.root)
+    }
+
+    /// Returns the ratio between the height of the AvlTree and the smallest height any binary
+    /// tree holding as many values can have. A perfectly balanced tree has a quality of `1.0`,
+    /// the AVL criterion keeps it below about `1.44`. An empty tree has a quality of `1.0`.
+    pub fn balance_quality(&self) -> f64 {
+        let optimal = usize::BITS - self.len().leading_zeros();
+        if optimal == 0 {
+            return 1.0;
+        }
+        self.height() as f64 / f64::from(optimal)
+    }
+
+    /// Rebuilds the AvlTree in O(n) into a perfectly balanced tree of the smallest possible
+    /// height, so that its [`AvlTree::balance_quality`] is `1.0` afterwards.
+    pub fn rebuild(&mut self) {
+        let len = self.len();
+        self.root = Self::build_sorted(&mut self.drain_iter(), len);
+    }
+
+    /// Checks if the AvlTree contains no elements, without walking the tree.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Counts the nodes that are exactly `depth` edges away from the root. The tree is
+    /// traversed level by level, stopping at the requested depth.
+    ///
+    /// ## Arguments
+    /// * `depth` The depth to count, where the root has depth 0
+    pub fn count_at_depth(&self, depth: usize) -> usize {
+        let mut level: Vec<NonNull<AvlNode<T>>> = self.root.into_iter().collect();
+        for _ in 0..depth {
+            if level.is_empty() {
+                break;
+            }
+            level = level
+                .into_iter()
+                .flat_map(|node| unsafe { [(*node.as_ptr()).left, (*node.as_ptr()).right] })
+                .flatten()
+                .collect();
+        }
+        level.len()
+    }
+
+    /// Joins two AvlTrees and a separating value into a single balanced AvlTree. Only the
+    /// spine of the higher tree is walked, so this runs in O(|height(left) - height(right)|).
+    ///
+    /// ## Arguments
+    /// * `left` Tree whose values are all strictly less than `mid`
+    /// * `mid` The separating value
+    /// * `right` Tree whose values are all strictly greater than `mid`
+    ///
+    /// The caller must uphold `left < mid < right` for every value. Violating this does not
+    /// cause undefined behaviour, but produces a tree that is no longer ordered and thus
+    /// breaks every lookup. It is checked in debug builds only.
+    pub fn join_with(left: Self, mid: T, right: Self) -> Self {
+        debug_assert!(left.max().is_none_or(|max| *max < mid));
+        debug_assert!(right.min().is_none_or(|min| *min > mid));
+        let node = Box::into_raw(Box::new(AvlNode {
+            value: mid,
+            left: None,
+            right: None,
+            height: 1,
+            size: 1,
+        }));
+        Self::join_around(left, node, right)
+    }
+
+    /// Joins two AvlTrees with `node` as the separating node, like [`AvlTree::join_with`]
+    /// without allocating. The children of `node` are overwritten, so it must not own any.
+    fn join_around(mut left: Self, node: *mut AvlNode<T>, mut right: Self) -> Self {
+        let left_height = link_height(&left.root);
+        let right_height = link_height(&right.root);
+        unsafe {
+            if left_height > right_height + 1 {
+                // descend the right spine of the left tree to a subtree matching the right tree
+                let mut prev_ptrs = Vec::<*mut AvlNode<T>>::new();
+                let mut current_tree: *mut Link<T> = &mut left.root;
+                while link_height(&*current_tree) > right_height + 1 {
+                    let current_node = (*current_tree).unwrap().as_ptr();
+                    prev_ptrs.push(current_node);
+                    current_tree = &mut (*current_node).right;
+                }
+                (*node).left = (*current_tree).take();
+                (*node).right = right.root.take();
+                (*node).update_height();
+                *current_tree = Some(NonNull::new_unchecked(node));
+
+                for ptr in prev_ptrs.into_iter().rev() {
+                    (*ptr).update_height();
+                    (*ptr).rebalance();
+                }
+                left
+            } else if right_height > left_height + 1 {
+                // descend the left spine of the right tree to a subtree matching the left tree
+                let mut prev_ptrs = Vec::<*mut AvlNode<T>>::new();
+                let mut current_tree: *mut Link<T> = &mut right.root;
+                while link_height(&*current_tree) > left_height + 1 {
+                    let current_node = (*current_tree).unwrap().as_ptr();
+                    prev_ptrs.push(current_node);
+                    current_tree = &mut (*current_node).left;
+                }
+                (*node).right = (*current_tree).take();
+                (*node).left = left.root.take();
+                (*node).update_height();
+                *current_tree = Some(NonNull::new_unchecked(node));
+
+                for ptr in prev_ptrs.into_iter().rev() {
+                    (*ptr).update_height();
+                    (*ptr).rebalance();
+                }
+                right
+            } else {
+                (*node).left = left.root.take();
+                (*node).right = right.root.take();
+                (*node).update_height();
+                Self::from_root(Some(NonNull::new_unchecked(node)))
+            }
+        }
+    }
+
+    /// Appends `other` to the AvlTree, where every value of `other` must be greater than every
+    /// value of the AvlTree. The smallest value of `other` is used to separate both trees for
+    /// [`AvlTree::join_with`], so this runs in O(height).
+    ///
+    /// ## Arguments
+    /// * `other` Tree whose values are all strictly greater than the values of this tree
+    /// ## Panics
+    /// If a value of `other` is not greater than every value of the AvlTree.
+    pub fn join(self, other: Self) -> Self {
+        assert!(
+            Self::joinable(&self, &other),
+            "the joined tree does not lie strictly above the tree"
+        );
+        unsafe { self.join_unchecked(other) }
+    }
+
+    /// Appends `other` to the AvlTree like [`AvlTree::join`], but skips the check that the
+    /// values of `other` lie strictly above the values of the tree.
+    ///
+    /// ## Safety
+    /// Every value of `other` must be greater than every value of the AvlTree. Otherwise the
+    /// result is no longer ordered and behaviour of every further operation is undefined.
+    /// The precondition is checked in debug builds only.
+    pub unsafe fn join_unchecked(self, mut other: Self) -> Self {
+        debug_assert!(
+            Self::joinable(&self, &other),
+            "the joined tree does not lie strictly above the tree"
+        );
+        match other.take_outermost(false) {
+            None => self,
+            Some(mid) => Self::join_with(self, mid, other),
+        }
+    }
+
+    /// Checks if every value of `right` is greater than every value of `left`.
+    fn joinable(left: &Self, right: &Self) -> bool {
+        match (left.max(), right.min()) {
+            (Some(max), Some(min)) => max < min,
+            _ => true,
+        }
+    }
+
+    /// Removes the smallest value from the tree, or the greatest one if `rightmost` is set,
+    /// and rebalances the spine leading to it.
+    fn take_outermost(&mut self, rightmost: bool) -> Option<T> {
+        let mut current_tree: *mut Link<T> = &mut self.root;
+        let mut prev_ptrs = Vec::<*mut AvlNode<T>>::new();
+        let removed = unsafe {
+            let mut node = (*current_tree)?;
+            loop {
+                let (outer, inner) = if rightmost {
+                    (&mut (*node.as_ptr()).right, (*node.as_ptr()).left)
+                } else {
+                    (&mut (*node.as_ptr()).left, (*node.as_ptr()).right)
+                };
+                match *outer {
+                    Some(child) => {
+                        prev_ptrs.push(node.as_ptr());
+                        current_tree = outer;
+                        node = child;
+                    }
+                    None => {
+                        *current_tree = inner;
+                        break;
+                    }
+                }
+            }
+            Box::from_raw(node.as_ptr()).value
+        };
+
+        for ptr in prev_ptrs.into_iter().rev() {
+            unsafe {
+                let node = &mut *ptr;
+                node.update_height();
+                node.rebalance();
+            }
+        }
+
+        Some(removed)
+    }
+
+    /// Checks if the exact shape of `pattern` appears in the AvlTree. The subtree rooted at the
+    /// node holding the root value of `pattern` must match `pattern` node for node, i.e. with
+    /// the same values, the same children and the same leaves. An empty `pattern` is always
+    /// contained.
+    ///
+    /// ## Arguments
+    /// * `pattern` The tree whose structure to look for
+    pub fn contains_subtree(&self, pattern: &Self) -> bool {
+        let pattern_root = match pattern.root {
+            None => return true,
+            Some(node) => node,
+        };
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                match (*node.as_ptr()).value.cmp(&(*pattern_root.as_ptr()).value) {
+                    Ordering::Greater => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return Self::same_shape(current_tree, &pattern.root),
+                    Ordering::Less => current_tree = &(*node.as_ptr()).right,
+                }
+            }
+        }
+        false
+    }
+
+    /// Compares two subtrees for equal values and structure, using an explicit stack so
+    /// that degenerate trees cannot overflow the call stack.
+    fn same_shape(tree: &Link<T>, pattern: &Link<T>) -> bool {
+        let mut stack = vec![(*tree, *pattern)];
+        while let Some(pair) = stack.pop() {
+            match pair {
+                (None, None) => {}
+                (Some(node), Some(pattern_node)) => unsafe {
+                    if (*node.as_ptr()).value != (*pattern_node.as_ptr()).value {
+                        return false;
                     }
+                    stack.push(((*node.as_ptr()).left, (*pattern_node.as_ptr()).left));
+                    stack.push(((*node.as_ptr()).right, (*pattern_node.as_ptr()).right));
                 },
-                Some(ref current_node) => unsafe {
-                    if (*current_node.as_ptr()).left.is_some() {
-                        self.prev_nodes.push(&(*current_node.as_ptr()));
-                        self.current_tree = &(*current_node.as_ptr()).left;
+                _ => return false,
+            }
+        }
+        true
+    }
 
-                        continue;
+    /// Detaches all nodes from the AvlTree in O(1), leaving it empty. The nodes are only freed
+    /// once the returned [`NodeGraveyard`] is dropped, which may happen on another thread.
+    pub fn take_nodes(&mut self) -> NodeGraveyard<T> {
+        NodeGraveyard {
+            tree: Self::from_root(self.root.take()),
+        }
+    }
+
+    /// Inserts a batch of values into the tree in place, keeping the existing elements and
+    /// their nodes. The tree and the batch are walked together: the batch is split at the
+    /// root of each subtree it reaches, subtrees receiving no values are left untouched and
+    /// the parts are joined again around the root, so rebalancing happens once per visited
+    /// node rather than once per value. For a batch of m values this runs in
+    /// O(m log(n / m + 1)) besides sorting the batch, compared to O(m log(n + m)) for
+    /// repeated inserts. Values out of order are sorted first, values that are already
+    /// present, or repeated within the batch, are skipped. Like other bulk operations, the
+    /// insertions are not reported to an observer or self check.
+    ///
+    /// ## Arguments
+    /// * `sorted` Values to insert, preferably in ascending order
+    /// ## Returns
+    /// The number of values that were inserted.
+    pub fn bulk_insert_sorted<I: IntoIterator<Item = T>>(&mut self, sorted: I) -> usize {
+        let mut values: Vec<T> = sorted.into_iter().collect();
+        // already sorted input is detected as a single run in O(m)
+        values.sort();
+        values.dedup();
+        let len = self.len();
+        let mut merged = Self::from_root(self.root.take())
+            .merge_sorted(&mut values.into_iter().peekable(), None);
+        self.root = merged.root.take();
+        self.len() - len
+    }
+
+    /// Inserts the values of `values` below the exclusive upper `bound` into the tree, see
+    /// [`AvlTree::bulk_insert_sorted`]. The values must be ascending and free of duplicates.
+    fn merge_sorted<I: Iterator<Item = T>>(
+        mut self,
+        values: &mut Peekable<I>,
+        bound: Option<&T>,
+    ) -> Self {
+        let below_bound = |value: &T| bound.is_none_or(|bound| value < bound);
+        if !values.peek().is_some_and(below_bound) {
+            return self;
+        }
+        let root = match self.root.take() {
+            Some(root) => root.as_ptr(),
+            None => {
+                let batch: Vec<T> = std::iter::from_fn(|| values.next_if(below_bound)).collect();
+                let len = batch.len();
+                return Self::from_root(Self::build_sorted(&mut batch.into_iter(), len));
+            }
+        };
+        unsafe {
+            let left = Self::from_root((*root).left.take());
+            let right = Self::from_root((*root).right.take());
+            let left = left.merge_sorted(values, Some(&(*root).value));
+            values.next_if(|value| *value == (*root).value);
+            let right = right.merge_sorted(values, bound);
+            Self::join_around(left, root, right)
+        }
+    }
+
+    /// Removes and returns the longest sorted prefix of values for which `pred` holds, stopping
+    /// at the first value for which it does not. The prefix is walked to find the boundary and
+    /// the tree is then split at its rank, so the remaining values keep their nodes and stay
+    /// balanced. For `k` drained values, this runs in O(k + height).
+    ///
+    /// ## Arguments
+    /// * `pred` Predicate deciding whether to keep draining
+    /// ## Returns
+    /// The drained values in ascending order.
+    pub fn drain_while<F: FnMut(&T) -> bool>(&mut self, mut pred: F) -> Vec<T> {
+        let boundary = self.iter().take_while(|value| pred(value)).count();
+        let (drained, mut rest) = Self::from_root(self.root.take()).split_at_rank(boundary);
+        self.root = rest.root.take();
+        drained.into_iter().collect()
+    }
+
+    /// Keeps the longest sorted prefix of values for which `pred` holds and drops all others.
+    ///
+    /// `pred` is assumed to be monotone over the sorted order, i.e. true for a prefix and false
+    /// for the rest. This allows to search the boundary by descending the tree once, asking
+    /// `pred` only about the values on that path, and to cut the tree there with a single
+    /// split. The kept values stay balanced and the dropped ones are freed, so this runs in
+    /// O(height) plus the time to free the dropped nodes. If `pred` is not monotone, the tree
+    /// stays valid, but which values are kept is unspecified.
+    ///
+    /// ## Arguments
+    /// * `pred` Predicate deciding which values to keep
+    pub fn retain_prefix<F: Fn(&T) -> bool>(&mut self, pred: F) {
+        let mut boundary = 0;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            let node = unsafe { &*node.as_ptr() };
+            if pred(&node.value) {
+                boundary += link_size(&node.left) + 1;
+                current_tree = &node.right;
+            } else {
+                current_tree = &node.left;
+            }
+        }
+        let (mut kept, mut dropped) = Self::from_root(self.root.take()).split_at_rank(boundary);
+        self.root = kept.root.take();
+        free_subtree(dropped.root.take());
+    }
+
+    /// Keeps only the values for which `f` returns true, given their zero-based position in
+    /// sorted order and the value itself. The survivors are rebuilt into a balanced tree.
+    ///
+    /// ## Arguments
+    /// * `f` Predicate deciding which values to keep
+    pub fn retain_indexed<F: FnMut(usize, &T) -> bool>(&mut self, mut f: F) {
+        let kept: Vec<T> = self
+            .drain_iter()
+            .enumerate()
+            .filter(|(index, value)| f(*index, value))
+            .map(|(_, value)| value)
+            .collect();
+        let len = kept.len();
+        self.root = Self::build_sorted(&mut kept.into_iter(), len);
+    }
+
+    /// Removes every value contained in `sorted_keys`, which must be in ascending order.
+    /// The tree and the keys are walked together in O(n + m) and the remaining values are
+    /// rebuilt into a balanced tree, which beats repeated single removals for large batches.
+    ///
+    /// ## Arguments
+    /// * `sorted_keys` Values to remove in ascending order
+    /// ## Returns
+    /// The number of values that were removed.
+    pub fn remove_sorted(&mut self, sorted_keys: &[T]) -> usize {
+        debug_assert!(
+            sorted_keys.windows(2).all(|pair| pair[0] <= pair[1]),
+            "keys are not sorted in ascending order"
+        );
+        let mut keys = sorted_keys.iter().peekable();
+        let mut removed = 0;
+        let mut survivors = Vec::new();
+        for value in self.drain_iter() {
+            while keys.next_if(|key| **key < value).is_some() {}
+            if keys.peek() == Some(&&value) {
+                removed += 1;
+            } else {
+                survivors.push(value);
+            }
+        }
+        let len = survivors.len();
+        self.root = Self::build_sorted(&mut survivors.into_iter(), len);
+        removed
+    }
+
+    /// Reconstructs an AvlTree with the exact shape described by a level-order array as
+    /// produced by [`AvlTree::to_level_order_options`]. The heights are recomputed and the
+    /// shape is validated to be an ordered and balanced tree.
+    ///
+    /// ## Arguments
+    /// * `level_order` Values in level order, with `None` for missing children
+    ///
+    /// ## Returns
+    /// The tree, or the reason the array does not describe a valid AvlTree.
+    pub fn from_level_order(level_order: Vec<Option<T>>) -> Result<Self, LevelOrderError> {
+        let mut level_order = level_order.into_iter();
+        let mut tree = Self::new();
+        let root = match level_order.next().flatten() {
+            None if level_order.next().is_none() => return Ok(tree),
+            None => return Err(LevelOrderError::TrailingEntries),
+            Some(value) => Box::into_raw(Box::new(AvlNode {
+                value,
+                left: None,
+                right: None,
+                height: 1,
+                size: 1,
+            })),
+        };
+        let mut nodes = vec![root];
+        let mut index = 0;
+        while index < nodes.len() {
+            let parent = nodes[index];
+            index += 1;
+            for is_right in [false, true] {
+                if let Some(Some(value)) = level_order.next() {
+                    let child = Box::into_raw(Box::new(AvlNode {
+                        value,
+                        left: None,
+                        right: None,
+                        height: 1,
+                        size: 1,
+                    }));
+                    unsafe {
+                        let link = NonNull::new_unchecked(child);
+                        if is_right {
+                            (*parent).right = Some(link);
+                        } else {
+                            (*parent).left = Some(link);
+                        }
                     }
-                    if (*current_node.as_ptr()).right.is_some() {
-                        self.current_tree = &(*current_node.as_ptr()).right;
-                        return Some(&(*current_node.as_ptr()));
+                    nodes.push(child);
+                }
+            }
+        }
+        // children come after their parents in level order, so heights can be fixed backwards
+        for node in nodes.into_iter().rev() {
+            unsafe { (*node).update_height() };
+        }
+        tree.root = NonNull::new(root);
+        if level_order.next().is_some() {
+            return Err(LevelOrderError::TrailingEntries);
+        }
+        match tree.validate() {
+            Ok(()) => {}
+            Err(Violation::Unordered { .. }) => return Err(LevelOrderError::Unordered),
+            Err(Violation::Unbalanced { .. }) => return Err(LevelOrderError::Unbalanced),
+            Err(_) => unreachable!("heights and sizes were just recomputed"),
+        }
+        Ok(tree)
+    }
+
+    /// Removes all values within `range` and returns them as a new balanced AvlTree. Both the
+    /// extracted values and the remaining ones are rebuilt in O(n).
+    ///
+    /// ## Arguments
+    /// * `range` The bounds of the values to extract
+    pub fn take_range<R: RangeBounds<T>>(&mut self, range: R) -> Self {
+        let (mut taken, mut kept) = (Vec::new(), Vec::new());
+        for value in self.drain_iter() {
+            if range.contains(&value) {
+                taken.push(value);
+            } else {
+                kept.push(value);
+            }
+        }
+        let (taken_len, kept_len) = (taken.len(), kept.len());
+        self.root = Self::build_sorted(&mut kept.into_iter(), kept_len);
+        Self::from_root(Self::build_sorted(&mut taken.into_iter(), taken_len))
+    }
+
+    /// Builds a perfectly balanced subtree from the next `len` values of `values`, which
+    /// must be strictly ascending. Returns the link to the root of the subtree.
+    fn build_sorted<I: Iterator<Item = T>>(values: &mut I, len: usize) -> Link<T> {
+        if len == 0 {
+            return None;
+        }
+        let left = Self::build_sorted(values, len / 2);
+        let value = values.next()?;
+        let right = Self::build_sorted(values, len - len / 2 - 1);
+        let mut node = Box::new(AvlNode {
+            value,
+            left,
+            right,
+            height: 1,
+            size: 1,
+        });
+        node.update_height();
+        unsafe { Some(NonNull::new_unchecked(Box::into_raw(node))) }
+    }
+
+    /// Builds a perfectly balanced AvlTree from values in strictly ascending order in O(n).
+    /// The middle value of each range becomes the root of its subtree, so unlike collecting
+    /// into the tree no comparisons or rotations are needed.
+    ///
+    /// ## Arguments
+    /// * `iter` The values in strictly ascending order
+    ///
+    /// ## Panics
+    /// In debug builds, if the values are not strictly ascending.
+    pub fn from_sorted(iter: impl IntoIterator<Item = T>) -> Self {
+        let values: Vec<T> = iter.into_iter().collect();
+        debug_assert!(
+            values.windows(2).all(|pair| pair[0] < pair[1]),
+            "values are not in strictly ascending order"
+        );
+        let len = values.len();
+        Self::from_root(Self::build_sorted(&mut values.into_iter(), len))
+    }
+
+    /// Builds an AvlTree from `iter` in which the last of several equal values is kept,
+    /// whereas collecting into the tree keeps the first one. This matters if the ordering of
+    /// `T` only looks at a key and ignores the rest of the value. The values are sorted in
+    /// O(n log n) and the tree is built with [`AvlTree::from_sorted`], without rotations.
+    ///
+    /// ## Arguments
+    /// * `iter` The values in any order
+    pub fn from_iter_last_wins(iter: impl IntoIterator<Item = T>) -> Self {
+        let mut values: Vec<T> = iter.into_iter().collect();
+        // the sort is stable, so after reversing the last of equal values comes first
+        values.reverse();
+        values.sort();
+        values.dedup();
+        Self::from_sorted(values)
+    }
+
+    /// Converts a `BTreeSet` into a perfectly balanced AvlTree in O(n). The set is already
+    /// sorted and free of duplicates, so the tree is built directly without any rotations.
+    pub fn from_btreeset(set: BTreeSet<T>) -> Self {
+        let len = set.len();
+        Self::from_root(Self::build_sorted(&mut set.into_iter(), len))
+    }
+
+    /// Consumes the AvlTree and moves its values into a `BTreeSet`.
+    pub fn into_btreeset(self) -> BTreeSet<T> {
+        self.into_iter().collect()
+    }
+
+    /// Removes all values from the tree and returns them in sorted order, freeing every node.
+    fn drain_sorted(&mut self) -> Vec<T> {
+        self.drain_iter().collect()
+    }
+
+    /// Detaches all nodes from the tree and returns an iterator moving the values out of them
+    /// in sorted order.
+    fn drain_iter(&mut self) -> IntoIter<T> {
+        IntoIter {
+            remaining: self.len(),
+            subtrees: self.root.take().into_iter().collect(),
+        }
+    }
+
+    /// Consumes the tree and returns its values in descending order. The tree is walked in
+    /// reverse, moving each value out and freeing its node as it is visited.
+    pub fn into_sorted_vec_desc(mut self) -> Vec<T> {
+        let mut values = Vec::with_capacity(self.len());
+        let mut prev_nodes = Vec::new();
+        let mut current_tree = self.root.take();
+        loop {
+            while let Some(node) = current_tree {
+                prev_nodes.push(node);
+                current_tree = unsafe { (*node.as_ptr()).right };
+            }
+            match prev_nodes.pop() {
+                None => return values,
+                Some(node) => {
+                    // the right subtree is already freed, so the node can be reclaimed
+                    let node = unsafe { Box::from_raw(node.as_ptr()) };
+                    current_tree = node.left;
+                    values.push(node.value);
+                }
+            }
+        }
+    }
+
+    /// Consumes this tree and `other` and merges their values into a single sorted `Vec`
+    /// without duplicates. For values present in both trees, the one of `self` is kept.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to merge with
+    pub fn into_merged_vec(mut self, mut other: Self) -> Vec<T> {
+        let mut merged = Vec::with_capacity(self.len() + other.len());
+        let mut left = self.drain_iter().peekable();
+        let mut right = other.drain_iter().peekable();
+        loop {
+            let next = match (left.peek(), right.peek()) {
+                (None, None) => return merged,
+                (Some(_), None) => left.next(),
+                (None, Some(_)) => right.next(),
+                (Some(l), Some(r)) => match l.cmp(r) {
+                    Ordering::Less => left.next(),
+                    Ordering::Greater => right.next(),
+                    Ordering::Equal => {
+                        right.next();
+                        left.next()
                     }
-                    self.current_tree = &None;
-                    return Some(&(*current_node.as_ptr()));
                 },
+            };
+            if let Some(value) = next {
+                debug_assert!(
+                    merged.last().is_none_or(|last| *last < value),
+                    "merge emitted a duplicate or out of order value"
+                );
+                merged.push(value);
+            }
+        }
+    }
+
+    /// Consumes this tree and `other` and returns a balanced AvlTree holding the values of
+    /// both. The values are moved out of the freed nodes and the result is built from their
+    /// sorted merge in O(n + m), so unlike a union of borrowed trees no value is cloned.
+    /// For values present in both trees, the one of `self` is kept.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to unite with
+    pub fn into_union(self, other: Self) -> Self {
+        let merged = self.into_merged_vec(other);
+        let len = merged.len();
+        Self::from_root(Self::build_sorted(&mut merged.into_iter(), len))
+    }
+
+    /// Consumes the tree and splits it at the root into the root value and the left and
+    /// right subtrees. Both subtrees are valid AvlTrees and take over their nodes as is.
+    /// [`AvlTree::join_with`] reassembles the parts into the original tree.
+    ///
+    /// ## Returns
+    /// `(root, left, right)`, where `root` is `None` for an empty tree.
+    pub fn into_parts(mut self) -> (Option<T>, Self, Self) {
+        match self.root.take() {
+            None => (None, Self::new(), Self::new()),
+            Some(root) => {
+                let root = unsafe { Box::from_raw(root.as_ptr()) };
+                (
+                    Some(root.value),
+                    Self::from_root(root.left),
+                    Self::from_root(root.right),
+                )
+            }
+        }
+    }
+
+    /// Splits the tree into the values less than `key` and the values greater than `key` by
+    /// joining the subtrees along the search path, in O(height) joins. A value equal to
+    /// `key` ends up in the lower tree if `key_in_lower` is set, else in the upper one.
+    fn split_at(self, key: &T, key_in_lower: bool) -> (Self, Self) {
+        match self.into_parts() {
+            (None, _, _) => (Self::new(), Self::new()),
+            (Some(root), left, right) => {
+                let goes_lower = match root.cmp(key) {
+                    Ordering::Less => true,
+                    Ordering::Equal => key_in_lower,
+                    Ordering::Greater => false,
+                };
+                if goes_lower {
+                    let (lower, upper) = right.split_at(key, key_in_lower);
+                    (Self::join_with(left, root, lower), upper)
+                } else {
+                    let (lower, upper) = left.split_at(key, key_in_lower);
+                    (lower, Self::join_with(upper, root, right))
+                }
+            }
+        }
+    }
+
+    /// Moves every value strictly greater than `key` into a new AvlTree, keeping `key` itself
+    /// and all smaller values. Both trees stay balanced, as the split only joins the subtrees
+    /// along the search path for `key`.
+    ///
+    /// ## Arguments
+    /// * `key` The greatest value to keep, which need not be in the AvlTree
+    /// ## Returns
+    /// A tree holding the values greater than `key`.
+    pub fn split_after(&mut self, key: &T) -> Self {
+        let (mut lower, upper) = Self::from_root(self.root.take()).split_at(key, true);
+        self.root = lower.root.take();
+        upper
+    }
+
+    /// Splits the tree into its `k` smallest values and the remaining ones. Like
+    /// [`AvlTree::split_at`], it only joins the subtrees along the path to the `k`-th value,
+    /// which is found through the cached subtree sizes instead of comparisons.
+    fn split_at_rank(self, k: usize) -> (Self, Self) {
+        match self.into_parts() {
+            (None, _, _) => (Self::new(), Self::new()),
+            (Some(root), left, right) => {
+                let left_len = left.len();
+                if k > left_len {
+                    let (lower, upper) = right.split_at_rank(k - left_len - 1);
+                    (Self::join_with(left, root, lower), upper)
+                } else {
+                    let (lower, upper) = left.split_at_rank(k);
+                    (lower, Self::join_with(upper, root, right))
+                }
+            }
+        }
+    }
+
+    /// Caps the AvlTree at `max` values by evicting the largest ones. The tree is split at
+    /// rank `max`, so both the kept and the evicted values stay balanced in O(height) joins.
+    ///
+    /// ## Arguments
+    /// * `max` The number of values to keep at most
+    /// ## Returns
+    /// The evicted values in sorted order, empty if the tree held at most `max` values.
+    pub fn shrink_to_max_len(&mut self, max: usize) -> Vec<T> {
+        if self.len() <= max {
+            return Vec::new();
+        }
+        let (mut kept, evicted) = Self::from_root(self.root.take()).split_at_rank(max);
+        self.root = kept.root.take();
+        evicted.into_iter().collect()
+    }
+
+    /// Retrieves the smallest value by walking the left spine of the tree in O(height).
+    /// Returns `None` if the tree is empty.
+    pub fn min(&self) -> Option<&T> {
+        let mut current_node = self.root?;
+        unsafe {
+            while let Some(left) = (*current_node.as_ptr()).left {
+                current_node = left;
+            }
+            Some(&(*current_node.as_ptr()).value)
+        }
+    }
+
+    /// Retrieves the greatest stored value that is less than or equal to `value` in O(height).
+    ///
+    /// ## Arguments
+    /// * `value` The bound, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// The stored value equal to `value` if present, else the closest smaller one, or `None`
+    /// if every stored value is greater.
+    pub fn floor<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<&T> {
+        let mut candidate = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => current_tree = &(*node.as_ptr()).left,
+                    Ordering::Equal => return Some(&(*node.as_ptr()).value),
+                    Ordering::Greater => {
+                        candidate = Some(&(*node.as_ptr()).value);
+                        current_tree = &(*node.as_ptr()).right;
+                    }
+                }
+            }
+        }
+        candidate
+    }
+
+    /// Retrieves the smallest stored value that is greater than or equal to `value` in
+    /// O(height).
+    ///
+    /// ## Arguments
+    /// * `value` The bound, either a `T` or any query type comparable against `T`
+    /// ## Returns
+    /// The stored value equal to `value` if present, else the closest greater one, or `None`
+    /// if every stored value is smaller.
+    pub fn ceil<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<&T> {
+        let mut candidate = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                match value.compare(&(*node.as_ptr()).value) {
+                    Ordering::Less => {
+                        candidate = Some(&(*node.as_ptr()).value);
+                        current_tree = &(*node.as_ptr()).left;
+                    }
+                    Ordering::Equal => return Some(&(*node.as_ptr()).value),
+                    Ordering::Greater => current_tree = &(*node.as_ptr()).right,
+                }
+            }
+        }
+        candidate
+    }
+
+    /// Retrieves the greatest stored value that is strictly less than `value` in O(height).
+    /// Unlike [`AvlTree::floor`], a stored value equal to `value` is skipped.
+    ///
+    /// ## Arguments
+    /// * `value` The bound, either a `T` or any query type comparable against `T`
+    pub fn predecessor<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<&T> {
+        let mut candidate = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                if value.compare(&(*node.as_ptr()).value) == Ordering::Greater {
+                    candidate = Some(&(*node.as_ptr()).value);
+                    current_tree = &(*node.as_ptr()).right;
+                } else {
+                    current_tree = &(*node.as_ptr()).left;
+                }
+            }
+        }
+        candidate
+    }
+
+    /// Retrieves the smallest stored value that is strictly greater than `value` in
+    /// O(height). Unlike [`AvlTree::ceil`], a stored value equal to `value` is skipped.
+    ///
+    /// ## Arguments
+    /// * `value` The bound, either a `T` or any query type comparable against `T`
+    pub fn successor<Q: ?Sized + Comparable<T>>(&self, value: &Q) -> Option<&T> {
+        let mut candidate = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                if value.compare(&(*node.as_ptr()).value) == Ordering::Less {
+                    candidate = Some(&(*node.as_ptr()).value);
+                    current_tree = &(*node.as_ptr()).left;
+                } else {
+                    current_tree = &(*node.as_ptr()).right;
+                }
+            }
+        }
+        candidate
+    }
+
+    /// Removes and returns the smallest value, rebalancing the left spine of the tree.
+    /// Returns `None` if the tree is empty.
+    pub fn pop_min(&mut self) -> Option<T> {
+        let min = self.take_outermost(false)?;
+        Some(self.notify_removed(min))
+    }
+
+    /// Removes and returns the greatest value, rebalancing the right spine of the tree.
+    /// Returns `None` if the tree is empty.
+    pub fn pop_max(&mut self) -> Option<T> {
+        let max = self.take_outermost(true)?;
+        Some(self.notify_removed(max))
+    }
+
+    /// Removes and returns both the smallest and the greatest value, e.g. to process the tree
+    /// from both ends inwards. Each removal only rebalances the spine leading to its value.
+    ///
+    /// ## Returns
+    /// `(min, max)`, where `max` is `None` if the tree held a single value and both are `None`
+    /// if it was empty.
+    pub fn pop_min_max(&mut self) -> (Option<T>, Option<T>) {
+        let min = self.pop_min();
+        let max = self.pop_max();
+        (min, max)
+    }
+
+    /// Retrieves the greatest value by walking the right spine of the tree in O(height).
+    /// Returns `None` if the tree is empty.
+    pub fn max(&self) -> Option<&T> {
+        let mut current_node = self.root?;
+        unsafe {
+            while let Some(right) = (*current_node.as_ptr()).right {
+                current_node = right;
+            }
+            Some(&(*current_node.as_ptr()).value)
+        }
+    }
+}
+
+impl<T: Ord + Hash> AvlTree<T> {
+    /// Returns a 64 bit fingerprint of the values in sorted order, e.g. for memoization keyed
+    /// on the contents of the tree. Unlike `DefaultHasher`, the fixed FNV-1a hash used here
+    /// yields the same result across runs and processes on the same platform.
+    pub fn content_fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        for value in self.iter() {
+            value.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+impl<T: Ord + Clone> AvlTree<T> {
+    /// Inserts a sorted batch of values that all fall into the gap between two neighbouring
+    /// values of the tree. The batch is built into a balanced subtree and spliced in by
+    /// splitting the tree at the gap and joining the parts, instead of rebalancing after
+    /// every single insertion.
+    ///
+    /// ## Arguments
+    /// * `sorted_batch` Strictly ascending values, none of which may lie between the smallest
+    ///   and greatest value of the batch in the tree. This is checked in debug builds only.
+    pub fn insert_gap_batch(&mut self, sorted_batch: &[T]) {
+        let (first, last) = match (sorted_batch.first(), sorted_batch.last()) {
+            (Some(first), Some(last)) => (first.clone(), last.clone()),
+            _ => return,
+        };
+        debug_assert!(
+            sorted_batch.windows(2).all(|pair| pair[0] < pair[1]),
+            "batch is not strictly ascending"
+        );
+        debug_assert!(
+            self.iter_from(&first)
+                .next()
+                .is_none_or(|next| *next > last),
+            "batch does not fit into a gap of the tree"
+        );
+
+        // only the nodes are split off, so the observer stays with the tree
+        let (lower, upper) = Self::from_root(self.root.take()).split_at(&first, false);
+        let mut joined = if sorted_batch.len() == 1 {
+            Self::join_with(lower, first, upper)
+        } else {
+            let inner = &sorted_batch[1..sorted_batch.len() - 1];
+            let inner =
+                Self::from_root(Self::build_sorted(&mut inner.iter().cloned(), inner.len()));
+            Self::join_with(Self::join_with(lower, first, inner), last, upper)
+        };
+        self.root = joined.root.take();
+    }
+
+    /// Groups clones of the values by `key` into one balanced AvlTree per key. The values of
+    /// each group are collected in sorted order, so every tree is built in linear time.
+    ///
+    /// ## Arguments
+    /// * `key` Function computing the group of a value
+    pub fn partition_by<K: Ord + Hash, F: Fn(&T) -> K>(&self, key: F) -> HashMap<K, Self> {
+        let mut groups: HashMap<K, Vec<T>> = HashMap::new();
+        for value in self.iter() {
+            groups.entry(key(value)).or_default().push(value.clone());
+        }
+        groups
+            .into_iter()
+            .map(|(key, values)| {
+                let len = values.len();
+                (
+                    key,
+                    Self::from_root(Self::build_sorted(&mut values.into_iter(), len)),
+                )
+            })
+            .collect()
+    }
+}
+
+impl<T: Ord + Clone> AvlTree<T> {
+    /// Serializes the exact shape of the AvlTree as a level-order array, where `None` marks a
+    /// missing child of an existing node and trailing `None`s are omitted. This is the format
+    /// commonly used to write down binary trees, e.g. `[2, 1, 3, None, None, None, 4]`.
+    pub fn to_level_order_options(&self) -> Vec<Option<T>> {
+        let mut level_order = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(self.root);
+        while let Some(link) = queue.pop_front() {
+            match link {
+                None => level_order.push(None),
+                Some(node) => unsafe {
+                    level_order.push(Some((*node.as_ptr()).value.clone()));
+                    queue.push_back((*node.as_ptr()).left);
+                    queue.push_back((*node.as_ptr()).right);
+                },
+            }
+        }
+        while let Some(None) = level_order.last() {
+            level_order.pop();
+        }
+        level_order
+    }
+
+    /// Returns an owning iterator over a sorted copy of the values taken right now.
+    /// Unlike [`AvlTree::iter`], it does not borrow the tree, so the tree can be modified
+    /// while the snapshot is consumed without affecting its output.
+    pub fn snapshot_iter(&self) -> std::vec::IntoIter<T> {
+        self.iter().cloned().collect::<Vec<_>>().into_iter()
+    }
+
+    /// Creates a deep copy of the AvlTree that is rebuilt into a perfectly balanced shape of
+    /// minimal height, instead of mirroring the possibly lopsided shape of the original.
+    pub fn clone_compact(&self) -> Self {
+        Self::from_root(Self::build_sorted(&mut self.iter().cloned(), self.len()))
+    }
+
+    /// Returns a new AvlTree holding the values of both trees, each value only once. Both
+    /// trees are merged in sorted order and the result is built with [`AvlTree::from_sorted`]
+    /// in O(n + m). For values present in both trees, the one of `self` is cloned.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to unite with
+    pub fn union(&self, other: &AvlTree<T>) -> AvlTree<T> {
+        let mut merged = Vec::with_capacity(self.len() + other.len());
+        let mut left = self.iter().peekable();
+        let mut right = other.iter().peekable();
+        loop {
+            let next = match (left.peek(), right.peek()) {
+                (None, None) => break,
+                (Some(_), None) => left.next(),
+                (None, Some(_)) => right.next(),
+                (Some(l), Some(r)) => match l.cmp(r) {
+                    Ordering::Less => left.next(),
+                    Ordering::Greater => right.next(),
+                    Ordering::Equal => {
+                        right.next();
+                        left.next()
+                    }
+                },
+            };
+            merged.extend(next.cloned());
+        }
+        Self::from_sorted(merged)
+    }
+
+    /// Returns a new AvlTree holding the values present in both trees. Both trees are walked
+    /// side by side in sorted order, skipping ahead in `other` to each value of `self`, and
+    /// the result is built with [`AvlTree::from_sorted`] in O(n + m).
+    ///
+    /// ## Arguments
+    /// * `other` The tree to intersect with
+    pub fn intersection(&self, other: &AvlTree<T>) -> AvlTree<T> {
+        let mut common = Vec::new();
+        let mut right = other.iter().peekable();
+        for value in self.iter() {
+            while right.next_if(|r| *r < value).is_some() {}
+            if right.next_if_eq(&value).is_some() {
+                common.push(value.clone());
+            }
+        }
+        Self::from_sorted(common)
+    }
+
+    /// Returns a new AvlTree holding the values of `self` that are not in `other`. Like
+    /// [`AvlTree::intersection`], both trees are walked side by side in sorted order and the
+    /// result is built with [`AvlTree::from_sorted`] in O(n + m).
+    ///
+    /// ## Arguments
+    /// * `other` The tree whose values are removed
+    pub fn difference(&self, other: &AvlTree<T>) -> AvlTree<T> {
+        let mut remaining = Vec::new();
+        let mut right = other.iter().peekable();
+        for value in self.iter() {
+            while right.next_if(|r| *r < value).is_some() {}
+            if right.next_if_eq(&value).is_none() {
+                remaining.push(value.clone());
+            }
+        }
+        Self::from_sorted(remaining)
+    }
+}
+
+/// Deterministic 64 bit [FNV-1a](https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function) hasher.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// A tree of [`Reverse`] values keeps its elements in descending order, i.e. `iter()` yields
+/// the largest value first. Internally the smallest `Reverse` value is the largest `T`, so the
+/// `logical_*` accessors report the extremes in terms of `T` instead.
+impl<T: Ord> AvlTree<Reverse<T>> {
+    /// Create a new AvlTree instance that orders its values from largest to smallest.
+    pub fn descending() -> Self {
+        Self::new()
+    }
+
+    /// Returns the smallest wrapped value, which is stored last in a descending tree.
+    pub fn logical_min(&self) -> Option<&T> {
+        self.max().map(|value| &value.0)
+    }
+
+    /// Returns the largest wrapped value, which is stored first in a descending tree.
+    pub fn logical_max(&self) -> Option<&T> {
+        self.min().map(|value| &value.0)
+    }
+}
+
+impl AvlTree<String> {
+    /// Finds all stored strings within the Levenshtein distance `max_dist` of `query`.
+    /// The strings are visited in sorted order, so neighbouring strings share prefixes and
+    /// reuse the dynamic programming rows computed for them, much like a walk over a trie.
+    /// Once every entry of a prefix's row exceeds `max_dist`, no string starting with that
+    /// prefix can match and the rest of it is skipped.
+    ///
+    /// ## Arguments
+    /// * `query` The string to compare the stored strings against.
+    /// * `max_dist` The largest number of insertions, deletions and substitutions allowed.
+    ///
+    /// ## Returns
+    /// The matching strings in ascending order.
+    pub fn find_within_edit_distance(&self, query: &str, max_dist: usize) -> Vec<&String> {
+        let query: Vec<char> = query.chars().collect();
+        // rows[i] holds the distances between the first i chars of `prefix` and every
+        // prefix of the query.
+        let mut rows: Vec<Vec<usize>> = vec![(0..=query.len()).collect()];
+        let mut prefix: Vec<char> = Vec::new();
+        let mut found = Vec::new();
+
+        for value in self.iter() {
+            let chars: Vec<char> = value.chars().collect();
+            let common = prefix
+                .iter()
+                .zip(&chars)
+                .take_while(|(a, b)| a == b)
+                .count();
+            prefix.truncate(common);
+            rows.truncate(common + 1);
+
+            for &c in &chars[common..] {
+                let last = &rows[rows.len() - 1];
+                if last.iter().all(|&dist| dist > max_dist) {
+                    break;
+                }
+                let mut row = Vec::with_capacity(last.len());
+                row.push(last[0] + 1);
+                for (i, &q) in query.iter().enumerate() {
+                    let substitute = last[i] + usize::from(q != c);
+                    row.push(substitute.min(last[i + 1] + 1).min(row[i] + 1));
+                }
+                prefix.push(c);
+                rows.push(row);
+            }
+
+            if prefix.len() == chars.len() && rows[rows.len() - 1][query.len()] <= max_dist {
+                found.push(value);
+            }
+        }
+        found
+    }
+}
+
+impl<T: Ord + Clone> AvlTree<(T, T)> {
+    /// Inserts the closed interval `lo..=hi` into a tree of disjoint intervals stored as
+    /// `(start, end)` pairs. Every stored interval that overlaps the new one or shares an
+    /// endpoint with it is removed and merged into it, so the tree keeps holding disjoint
+    /// intervals ordered by their start. For `k` merged intervals, this runs in
+    /// O((k + 1) * height). Intervals of integers that merely follow each other, like `1..=2`
+    /// and `3..=4`, do not share an endpoint and are kept apart.
+    ///
+    /// ## Arguments
+    /// * `lo` The first point of the interval
+    /// * `hi` The last point of the interval
+    /// ## Panics
+    /// If `hi` is less than `lo`.
+    pub fn insert_interval(&mut self, lo: T, hi: T) {
+        assert!(lo <= hi, "the interval ends before it starts");
+        let (mut lo, mut hi) = (lo, hi);
+        if let Some((start, end)) = self.interval_before(&lo).cloned() {
+            if end >= lo {
+                self.remove(&(start.clone(), end.clone()));
+                lo = start;
+                hi = hi.max(end);
+            }
+        }
+        // the remaining intervals starting within the merged interval overlap it
+        while let Some((start, end)) = self.interval_after(&lo).cloned() {
+            if start > hi {
+                break;
+            }
+            self.remove(&(start, end.clone()));
+            hi = hi.max(end);
+        }
+        self.insert((lo, hi));
+    }
+
+    /// Checks if `point` lies within one of the intervals of the tree in O(height), see
+    /// [`AvlTree::insert_interval`].
+    ///
+    /// ## Arguments
+    /// * `point` The point to look up
+    pub fn contains_point(&self, point: &T) -> bool {
+        self.interval_before(point)
+            .is_some_and(|(_, end)| point <= end)
+    }
+
+    /// Finds the interval with the greatest start not greater than `point`.
+    fn interval_before(&self, point: &T) -> Option<&(T, T)> {
+        let mut found = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            let node = unsafe { &*node.as_ptr() };
+            if node.value.0 <= *point {
+                found = Some(&node.value);
+                current_tree = &node.right;
+            } else {
+                current_tree = &node.left;
+            }
+        }
+        found
+    }
+
+    /// Finds the interval with the smallest start not less than `point`.
+    fn interval_after(&self, point: &T) -> Option<&(T, T)> {
+        let mut found = None;
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            let node = unsafe { &*node.as_ptr() };
+            if node.value.0 >= *point {
+                found = Some(&node.value);
+                current_tree = &node.left;
+            } else {
+                current_tree = &node.right;
+            }
+        }
+        found
+    }
+}
+
+impl<T: Ord + Debug> AvlTree<T> {
+    /// Create a new AvlTree instance that validates itself after every insertion and removal
+    /// if `enabled` is set, e.g. for fuzzing. The tree logs these operations and panics with
+    /// the log and the broken invariant as soon as [`AvlTree::validate`] fails, so silent
+    /// corruption turns into a reproducible failure. Each check walks the whole tree, making
+    /// every operation O(n). Like with observers, bulk operations are not checked and clones
+    /// start without checks.
+    ///
+    /// ## Arguments
+    /// * `enabled` Whether to check the tree, if not a plain AvlTree is returned
+    pub fn with_strict_checks(enabled: bool) -> Self {
+        let mut tree = Self::new();
+        if enabled {
+            tree.self_check = Some(SelfCheck {
+                on_violation: Box::new(|violation, log| {
+                    panic!("invalid AvlTree: {:?} after {:?}", violation, log)
+                }),
+                describe: Some(|value| format!("{:?}", value)),
+                log: Vec::new(),
+            });
+        }
+        tree
+    }
+}
+
+impl<T: Ord + Display> AvlTree<T> {
+    /// Renders the AvlTree top-down with box-drawing characters, similar to the `tree`
+    /// command. The left child of a node is listed before its right child and a missing
+    /// child is shown as `∅` if its sibling exists. An empty tree renders as an empty string.
+    pub fn pretty_print(&self) -> String {
+        let mut output = String::new();
+        let root = match self.root {
+            None => return output,
+            Some(root) => root,
+        };
+        unsafe {
+            output.push_str(&format!("{}\n", (*root.as_ptr()).value));
+        }
+        // pending children with the prefix of their line, right children are pushed first so
+        // that the left subtree is printed completely before them
+        let mut stack = Vec::new();
+        Self::push_children(root, String::new(), &mut stack);
+        while let Some((child, prefix, last)) = stack.pop() {
+            let (connector, indent) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            match child {
+                None => output.push_str(&format!("{}{}∅\n", prefix, connector)),
+                Some(child) => unsafe {
+                    output.push_str(&format!(
+                        "{}{}{}\n",
+                        prefix,
+                        connector,
+                        (*child.as_ptr()).value
+                    ));
+                    Self::push_children(child, format!("{}{}", prefix, indent), &mut stack);
+                },
+            }
+        }
+        output
+    }
+
+    /// Pushes the children of `node` onto the stack of [`AvlTree::pretty_print`], unless it is a leaf.
+    fn push_children(
+        node: NonNull<AvlNode<T>>,
+        prefix: String,
+        stack: &mut Vec<(Link<T>, String, bool)>,
+    ) {
+        let (left, right) = unsafe { ((*node.as_ptr()).left, (*node.as_ptr()).right) };
+        if left.is_some() || right.is_some() {
+            stack.push((right, prefix.clone(), true));
+            stack.push((left, prefix, false));
+        }
+    }
+
+    /// Return a graphviz dotfile representation of the AvlTree.
+    pub fn as_dotfile(&self) -> Option<String> {
+        self.dotfile_with(Vec::new(), false)
+    }
+
+    /// Return a graphviz dotfile representation of the AvlTree styled with the given theme.
+    ///
+    /// ## Arguments
+    /// * `theme` The color preset to use, see [`DotTheme`]
+    pub fn as_dotfile_themed(&self, theme: DotTheme) -> Option<String> {
+        self.dotfile_with(theme.statements(), false)
+    }
+
+    /// Return a graphviz dotfile representation of the AvlTree in which every node violating
+    /// the avl criterion, i.e. with a balance factor of at least 2 in either direction, is
+    /// drawn in red. Trees built through [`AvlTree::insert`] never contain such nodes, but
+    /// trees built with [`AvlTree::insert_without_rebalancing`] show what an unbalanced tree
+    /// looks like.
+    pub fn dotfile_highlight_violations(&self) -> Option<String> {
+        self.dotfile_with(Vec::new(), true)
+    }
+
+    /// Return a graphviz dotfile representation of the AvlTree, optionally styled with a
+    /// theme and with the nodes violating the avl criterion drawn in red.
+    ///
+    /// ## Arguments
+    /// * `theme` The color preset to use, see [`DotTheme`], or `None` for the plain style
+    /// * `highlight_violations` Whether to highlight unbalanced nodes, see
+    ///   [`AvlTree::dotfile_highlight_violations`]
+    pub fn as_dotfile_styled(
+        &self,
+        theme: Option<DotTheme>,
+        highlight_violations: bool,
+    ) -> Option<String> {
+        let stmts = theme.map(DotTheme::statements).unwrap_or_default();
+        self.dotfile_with(stmts, highlight_violations)
+    }
+
+    /// Builds the dotfile of the AvlTree, placing `stmts` in front of the nodes and edges.
+    /// If `highlight_violations` is set, unbalanced nodes are colored red.
+    fn dotfile_with(&self, stmts: Vec<Stmt>, highlight_violations: bool) -> Option<String> {
+        if let Some(root) = self.root {
+            unsafe {
+                let mut graph = Graph::DiGraph {
+                    id: Id::Plain(String::from("AVL_Tree")),
+                    strict: true,
+                    stmts,
+                };
+                let mut queue = VecDeque::new();
+                queue.push_back(root);
+                while !queue.is_empty() {
+                    let node = queue.pop_front().unwrap();
+                    let attributes =
+                        if highlight_violations && (*node.as_ptr()).balance_factor().abs() >= 2 {
+                            vec![attribute("color", "red"), attribute("fontcolor", "red")]
+                        } else {
+                            Vec::new()
+                        };
+                    graph.add_stmt(Stmt::Node(Node::new(
+                        NodeId(Id::Plain((*node.as_ptr()).value.to_string()), None),
+                        attributes,
+                    )));
+                    if (*node.as_ptr()).left.is_some() {
+                        queue.push_back((*node.as_ptr()).left.unwrap());
+                        graph.add_stmt(Stmt::Edge(Edge {
+                            ty: EdgeTy::Pair(
+                                Vertex::N(NodeId(
+                                    Id::Plain((*node.as_ptr()).value.to_string()),
+                                    None,
+                                )),
+                                Vertex::N(NodeId(
+                                    Id::Plain(
+                                        (*(*node.as_ptr()).left.unwrap().as_ptr())
+                                            .value
+                                            .to_string(),
+                                    ),
+                                    None,
+                                )),
+                            ),
+                            attributes: Vec::new(),
+                        }));
+                    }
+                    if (*node.as_ptr()).right.is_some() {
+                        queue.push_back((*node.as_ptr()).right.unwrap());
+                        graph.add_stmt(Stmt::Edge(Edge {
+                            ty: EdgeTy::Pair(
+                                Vertex::N(NodeId(
+                                    Id::Plain((*node.as_ptr()).value.to_string()),
+                                    None,
+                                )),
+                                Vertex::N(NodeId(
+                                    Id::Plain(
+                                        (*(*node.as_ptr()).right.unwrap().as_ptr())
+                                            .value
+                                            .to_string(),
+                                    ),
+                                    None,
+                                )),
+                            ),
+                            attributes: Vec::new(),
+                        }));
+                    }
+                }
+                Some(graph.print(&mut PrinterContext::default()))
+            }
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T: Ord + 'a> AvlTree<T> {
+    /// Returns an iterator over the borrowed values in the tree.
+    /// The iterator performs an in-order depth traversal of the tree. It borrows the tree,
+    /// so the tree cannot be modified until the iterator is dropped; use
+    /// [`AvlTree::snapshot_iter`] to iterate over the values while modifying the tree.
+    pub fn iter(&'a self) -> Iter<'a, T> {
+        Iter {
+            prev_nodes: Vec::new(),
+            current_tree: &self.root,
+            next_nodes: Vec::new(),
+            back_tree: &self.root,
+            remaining: self.len(),
+        }
+    }
+
+    /// Returns an in-order iterator like [`AvlTree::iter`] that keeps its stack of pending
+    /// nodes in `scratch` instead of allocating a new one. The stack is handed back, emptied,
+    /// when the iterator is dropped, so iterating repeatedly with the same scratch buffer only
+    /// allocates until the buffer has grown to the height of the tree. The buffer only holds
+    /// pointers while an iterator uses it, so it does not borrow the tree and can be reused
+    /// across modifications of the tree and even for other trees.
+    ///
+    /// ## Arguments
+    /// * `scratch` Buffer to reuse for the traversal, see [`IterScratch`]
+    pub fn iter_with_scratch<'s>(
+        &'a self,
+        scratch: &'s mut IterScratch<T>,
+    ) -> ScratchIter<'a, 's, T> {
+        let mut iter = self.iter();
+        iter.prev_nodes = scratch.lend();
+        ScratchIter { iter, scratch }
+    }
+
+    /// Returns an iterator over the actual nodes in the tree.
+    /// The iterator performs an in-order depth traversal of the tree.
+    pub fn node_iter(&'a self) -> NodeIter<'a, T> {
+        NodeIter {
+            prev_nodes: Vec::new(),
+            current_tree: &self.root,
+        }
+    }
+
+    /// Returns an in-order iterator over the values in the tree together with the size of the
+    /// subtree rooted at their node, i.e. the node itself and all of its descendants.
+    pub fn iter_with_size(&'a self) -> impl Iterator<Item = (&'a T, usize)> + 'a {
+        self.node_iter().map(|node| (&node.value, node.size))
+    }
+
+    /// Returns an in-order iterator over the values within `range`, like `BTreeSet::range`.
+    /// The first and last value within the range are looked up in O(height) and only the
+    /// values between them are visited. A range whose start lies after its end is empty.
+    ///
+    /// ## Arguments
+    /// * `range` The bounds of the values to visit
+    pub fn range<R: RangeBounds<T>>(&'a self, range: R) -> Range<'a, T> {
+        let first = match range.start_bound() {
+            Bound::Included(start) => self.ceil(start),
+            Bound::Excluded(start) => self.successor(start),
+            Bound::Unbounded => self.min(),
+        };
+        let last = match range.end_bound() {
+            Bound::Included(end) => self.floor(end),
+            Bound::Excluded(end) => self.predecessor(end),
+            Bound::Unbounded => self.max(),
+        };
+        match (first, last) {
+            (Some(first), Some(last)) if first <= last => Range {
+                iter: self.iter_from(first),
+                last: Some(last),
+            },
+            _ => Range {
+                iter: Iter {
+                    prev_nodes: Vec::new(),
+                    current_tree: &None,
+                    next_nodes: Vec::new(),
+                    back_tree: &None,
+                    remaining: 0,
+                },
+                last: None,
+            },
+        }
+    }
+
+    /// Returns an in-order iterator starting at the smallest value that is not less than `start`.
+    fn iter_from<Q: ?Sized + Comparable<T>>(&'a self, start: &Q) -> Iter<'a, T> {
+        let mut prev_nodes = Vec::new();
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            unsafe {
+                if start.compare(&(*node.as_ptr()).value) == Ordering::Greater {
+                    current_tree = &(*node.as_ptr()).right;
+                } else {
+                    prev_nodes.push(&*node.as_ptr());
+                    current_tree = &(*node.as_ptr()).left;
+                }
+            }
+        }
+        Iter {
+            prev_nodes,
+            current_tree: &None,
+            next_nodes: Vec::new(),
+            back_tree: &self.root,
+            remaining: self.len() - self.rank(start),
+        }
+    }
+
+    /// Returns an in-order iterator starting at the `k`-th smallest value, counting from 0,
+    /// which is found through the cached subtree sizes. The iterator is empty if `k >= len()`.
+    fn iter_at_rank(&'a self, mut k: usize) -> Iter<'a, T> {
+        let remaining = self.len().saturating_sub(k);
+        let mut prev_nodes = Vec::new();
+        let mut current_tree = &self.root;
+        while let Some(node) = current_tree {
+            let node = unsafe { &*node.as_ptr() };
+            let left_size = link_size(&node.left);
+            if k > left_size {
+                k -= left_size + 1;
+                current_tree = &node.right;
+            } else {
+                prev_nodes.push(node);
+                current_tree = &node.left;
+            }
+        }
+        Iter {
+            prev_nodes,
+            current_tree: &None,
+            next_nodes: Vec::new(),
+            back_tree: &self.root,
+            remaining,
+        }
+    }
+
+    /// Returns an in-order iterator over the values whose zero-based positions lie within
+    /// `start_rank..end_rank`, e.g. to show rows 100 to 150 of a sorted listing. The first
+    /// value is found through the cached subtree sizes in O(height), instead of skipping over
+    /// all values before it.
+    ///
+    /// ## Arguments
+    /// * `start_rank` The position of the first value to visit
+    /// * `end_rank` The position after the last value to visit, nothing is visited if it does
+    ///   not lie after `start_rank`
+    pub fn select_range(
+        &'a self,
+        start_rank: usize,
+        end_rank: usize,
+    ) -> std::iter::Take<Iter<'a, T>> {
+        self.iter_at_rank(start_rank)
+            .take(end_rank.saturating_sub(start_rank))
+    }
+
+    /// Checks for each of the inclusive `ranges` whether at least one stored value lies
+    /// within it. The ranges must be sorted by their lower bound, so all of them are answered
+    /// by a single in-order walk starting at the first lower bound.
+    ///
+    /// ## Arguments
+    /// * `ranges` Pairs of inclusive lower and upper bounds, sorted by the lower bound
+    /// ## Returns
+    /// For each range, whether the AvlTree contains a value within it.
+    pub fn any_in_ranges(&'a self, ranges: &[(T, T)]) -> Vec<bool> {
+        debug_assert!(
+            ranges.windows(2).all(|pair| pair[0].0 <= pair[1].0),
+            "ranges are not sorted by their lower bound"
+        );
+        let mut values = match ranges.first() {
+            None => return Vec::new(),
+            Some((lo, _)) => self.iter_from(lo).peekable(),
+        };
+        ranges
+            .iter()
+            .map(|(lo, hi)| {
+                while values.next_if(|value| *value < lo).is_some() {}
+                values.peek().is_some_and(|value| *value <= hi)
+            })
+            .collect()
+    }
+
+    /// Looks up a batch of queries given in ascending order. The tree and the queries are
+    /// walked together in O(n + m), which is faster than separate lookups for large batches.
+    ///
+    /// ## Arguments
+    /// * `sorted_queries` Values to look up in ascending order
+    /// ## Returns
+    /// For each query, the stored value equal to it or `None`.
+    pub fn get_many_sorted(&'a self, sorted_queries: &'a [T]) -> Vec<Option<&'a T>> {
+        debug_assert!(
+            sorted_queries.windows(2).all(|pair| pair[0] <= pair[1]),
+            "queries are not sorted in ascending order"
+        );
+        let mut values = self.iter().peekable();
+        sorted_queries
+            .iter()
+            .map(|query| {
+                while values.next_if(|value| *value < query).is_some() {}
+                values.peek().copied().filter(|value| *value == query)
+            })
+            .collect()
+    }
+
+    /// Returns an iterator that merges the values of this tree and `other` in sorted order,
+    /// classifying each value by the tree(s) it is found in.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to merge with
+    pub fn merge_join(&'a self, other: &'a Self) -> MergeJoin<'a, T> {
+        MergeJoin {
+            left: self.iter().peekable(),
+            right: other.iter().peekable(),
+            last: None,
+        }
+    }
+
+    /// Counts the values present in both this tree and `other` with a sorted merge in
+    /// O(n + m), without building the intersection.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to intersect with
+    pub fn intersection_count(&'a self, other: &'a Self) -> usize {
+        self.merge_join(other)
+            .filter(|side| matches!(side, MergeSide::Both(_, _)))
+            .count()
+    }
+
+    /// Computes the [Jaccard index](https://en.wikipedia.org/wiki/Jaccard_index) of this tree
+    /// and `other`, i.e. the size of their intersection divided by the size of their union.
+    /// Two empty trees are considered identical and yield `1.0`.
+    ///
+    /// ## Arguments
+    /// * `other` The tree to compare with
+    pub fn jaccard(&'a self, other: &'a Self) -> f64 {
+        let (mut intersection, mut union) = (0, 0);
+        for side in self.merge_join(other) {
+            if let MergeSide::Both(_, _) = side {
+                intersection += 1;
+            }
+            union += 1;
+        }
+        if union == 0 {
+            1.0
+        } else {
+            intersection as f64 / union as f64
+        }
+    }
+
+    /// Returns an iterator over mutable handles to the nodes in the tree.
+    /// The iterator performs an in-order depth traversal of the tree.
+    ///
+    /// Mutating values through the handles must not change their relative order, as the
+    /// tree is not rebalanced or resorted afterwards. Breaking the order makes subsequent
+    /// lookups and insertions behave incorrectly.
+    pub fn node_iter_mut(&'a mut self) -> NodeIterMut<'a, T> {
+        NodeIterMut {
+            prev_nodes: Vec::new(),
+            current_tree: self.root,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over mutable references to the values in the tree, in sorted order.
+    ///
+    /// Mutating values through the references must not change their relative order, as the
+    /// tree is not rebalanced or resorted afterwards. Breaking the order makes subsequent
+    /// lookups and insertions behave incorrectly.
+    pub fn iter_mut(&'a mut self) -> IterMut<'a, T> {
+        IterMut {
+            prev_nodes: Vec::new(),
+            current_tree: self.root,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns a lookup handle that remembers the path to the last visited node. Lookups
+    /// through the handle resume the descent from the deepest node of that path whose subtree
+    /// can contain the new value, which saves most of the descent for runs of nearby values.
+    /// Scattered lookups gain nothing from the hint and are slower than [`AvlTree::get`], as
+    /// the path has to be unwound and recorded for each of them. The tree cannot be modified
+    /// while the handle exists, so the hint never goes stale.
+    pub fn access_hint(&'a self) -> AccessHint<'a, T> {
+        AccessHint {
+            root: &self.root,
+            path: Vec::new(),
+        }
+    }
+}
+
+impl<T: Ord> Drop for AvlTree<T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// In-order iterator that takes ownership of the nodes of an AvlTree, moving each value out
+/// and freeing its node as it is visited. Nodes not visited are freed when it is dropped.
+///
+/// The unvisited values are kept as a sequence of subtrees in sorted order. Either end expands
+/// its outermost subtree until that starts with a node without a child on that side, so both
+/// ends can be consumed and every node is only expanded once.
+pub struct IntoIter<T: Ord> {
+    subtrees: VecDeque<NonNull<AvlNode<T>>>,
+    remaining: usize,
+}
+
+impl<T: Ord> IntoIter<T> {
+    /// Moves the value out of a node whose children have been detached and frees the node.
+    fn take_value(&mut self, node: NonNull<AvlNode<T>>) -> T {
+        self.remaining -= 1;
+        unsafe { Box::from_raw(node.as_ptr()) }.value
+    }
+}
+
+impl<T: Ord> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.subtrees.pop_front()?;
+            let (left, right) = unsafe {
+                let node = &mut *node.as_ptr();
+                (node.left.take(), node.right.take())
+            };
+            if let Some(right) = right {
+                self.subtrees.push_front(right);
+            }
+            match left {
+                None => return Some(self.take_value(node)),
+                Some(left) => {
+                    self.subtrees.push_front(node);
+                    self.subtrees.push_front(left);
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: Ord> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.subtrees.pop_back()?;
+            let (left, right) = unsafe {
+                let node = &mut *node.as_ptr();
+                (node.left.take(), node.right.take())
+            };
+            self.subtrees.extend(left);
+            match right {
+                None => return Some(self.take_value(node)),
+                Some(right) => {
+                    self.subtrees.push_back(node);
+                    self.subtrees.push_back(right);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Ord> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: Ord> FusedIterator for IntoIter<T> {}
+
+impl<T: Ord> Drop for IntoIter<T> {
+    fn drop(&mut self) {
+        for subtree in self.subtrees.drain(..) {
+            free_subtree(Some(subtree));
+        }
+    }
+}
+
+/// Opaque owner of nodes detached from an AvlTree via [`AvlTree::take_nodes`].
+/// All nodes are freed when the graveyard is dropped.
+pub struct NodeGraveyard<T: Ord> {
+    tree: AvlTree<T>,
+}
+
+// The graveyard is the sole owner of its nodes and never hands out references to them,
+// so moving it to another thread is sound as long as the values themselves can be sent.
+// Its tree is created without an observer or self check, which might not be sendable.
+unsafe impl<T: Ord + Send> Send for NodeGraveyard<T> {}
+
+impl<T: Ord + Debug> Debug for AvlNode<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AvlNode")
+            .field("value", &self.value)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+impl<T: Ord + Debug> Debug for AvlTree<T> {
+    /// Prints the values of the tree in sorted order, e.g. `AvlTree {1, 2, 3}`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AvlTree ")?;
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Ord + Clone> Clone for AvlTree<T> {
+    /// Deep copies every node, preserving the exact shape of the tree. The nodes are copied
+    /// with an explicit stack, so even degenerate trees cannot overflow the call stack.
+    fn clone(&self) -> Self {
+        let mut tree = Self::new();
+        let root = match self.root {
+            None => return tree,
+            Some(root) => root,
+        };
+        let mut stack: Vec<(NonNull<AvlNode<T>>, *mut Link<T>)> = vec![(root, &mut tree.root)];
+        while let Some((source, target)) = stack.pop() {
+            unsafe {
+                let node = Box::into_raw(Box::new(AvlNode {
+                    value: (*source.as_ptr()).value.clone(),
+                    left: None,
+                    right: None,
+                    height: (*source.as_ptr()).height,
+                    size: (*source.as_ptr()).size,
+                }));
+                // linked right away, so a panicking clone still frees the copied nodes
+                *target = Some(NonNull::new_unchecked(node));
+                if let Some(left) = (*source.as_ptr()).left {
+                    stack.push((left, &mut (*node).left));
+                }
+                if let Some(right) = (*source.as_ptr()).right {
+                    stack.push((right, &mut (*node).right));
+                }
+            }
+        }
+        tree
+    }
+}
+
+impl<T: Ord> PartialEq for AvlTree<T> {
+    /// Two AvlTrees are equal if they hold equal values, regardless of the shape of the trees.
+    /// The sizes are compared first, so trees of different length are told apart in O(1).
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Ord> Eq for AvlTree<T> {}
+
+impl<T: Ord + Hash> Hash for AvlTree<T> {
+    /// Hashes the length and the values in sorted order, so that trees holding equal values
+    /// hash equally, regardless of their shape.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for value in self.iter() {
+            value.hash(state);
+        }
+    }
+}
+
+impl<T: Ord> Default for AvlTree<T> {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+/// In-order iterator over the values of an AvlTree. Once exhausted, it keeps returning `None`.
+/// It can be consumed from both ends, e.g. `iter().rev()` yields the values in descending order.
+pub struct Iter<'a, T: Ord> {
+    prev_nodes: Vec<&'a AvlNode<T>>,
+    current_tree: &'a Link<T>,
+    /// nodes whose value and left subtree are still to be visited from the back
+    next_nodes: Vec<&'a AvlNode<T>>,
+    back_tree: &'a Link<T>,
+    /// number of values not yet returned from either end, which keeps both ends from crossing
+    remaining: usize,
+}
+
+/// In-order iterator over the nodes of an AvlTree. Once exhausted, it keeps returning `None`.
+pub struct NodeIter<'a, T: Ord> {
+    prev_nodes: Vec<&'a AvlNode<T>>,
+    current_tree: &'a Link<T>,
+}
+
+impl<'a, T: Ord + 'a> Iterator for NodeIter<'a, T> {
+    type Item = &'a AvlNode<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = loop {
+            match *self.current_tree {
+                None => match self.prev_nodes.pop() {
+                    None => return None,
+                    Some(prev_node) => {
+                        self.current_tree = &prev_node.right;
+                        break prev_node;
+                    }
+                },
+                Some(ref current_node) => unsafe {
+                    if (*current_node.as_ptr()).left.is_some() {
+                        self.prev_nodes.push(&(*current_node.as_ptr()));
+                        self.current_tree = &(*current_node.as_ptr()).left;
+
+                        continue;
+                    }
+                    if (*current_node.as_ptr()).right.is_some() {
+                        self.current_tree = &(*current_node.as_ptr()).right;
+                        break &(*current_node.as_ptr());
+                    }
+                    self.current_tree = &None;
+                    break &(*current_node.as_ptr());
+                },
+            }
+        };
+        node.debug_assert_height();
+        Some(node)
+    }
+}
+
+impl<'a, T: Ord + 'a> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node: &'a AvlNode<T> = loop {
+            match *self.current_tree {
+                None => match self.prev_nodes.pop() {
+                    None => return None,
+                    Some(prev_node) => {
+                        self.current_tree = &prev_node.right;
+                        break prev_node;
+                    }
+                },
+                Some(ref current_node) => unsafe {
+                    if (*current_node.as_ptr()).left.is_some() {
+                        self.prev_nodes.push(&(*current_node.as_ptr()));
+                        self.current_tree = &(*current_node.as_ptr()).left;
+
+                        continue;
+                    }
+                    if (*current_node.as_ptr()).right.is_some() {
+                        self.current_tree = &(*current_node.as_ptr()).right;
+                        break &(*current_node.as_ptr());
+                    }
+                    self.current_tree = &None;
+                    break &(*current_node.as_ptr());
+                },
+            }
+        };
+        node.debug_assert_height();
+        Some(&node.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: Ord + 'a> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        while let Some(node) = self.back_tree {
+            let node = unsafe { &*node.as_ptr() };
+            self.next_nodes.push(node);
+            self.back_tree = &node.right;
+        }
+        let node = self.next_nodes.pop()?;
+        self.back_tree = &node.left;
+        node.debug_assert_height();
+        Some(&node.value)
+    }
+}
+
+impl<'a, T: Ord + 'a> ExactSizeIterator for Iter<'a, T> {}
+
+// NodeIter only returns `None` once the stack of pending nodes is empty and the current
+// subtree is the `&None` sentinel, Iter once no values remain. Neither is ever refilled
+// afterwards, so they stay exhausted.
+impl<'a, T: Ord + 'a> FusedIterator for NodeIter<'a, T> {}
+
+impl<'a, T: Ord + 'a> FusedIterator for Iter<'a, T> {}
+
+/// In-order iterator over the values of an AvlTree that borrows its stack, see
+/// [`AvlTree::iter_with_scratch`].
+pub struct ScratchIter<'a, 's, T: Ord> {
+    iter: Iter<'a, T>,
+    scratch: &'s mut IterScratch<T>,
+}
+
+impl<'a, T: Ord + 'a> Iterator for ScratchIter<'a, '_, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T: Ord + 'a> FusedIterator for ScratchIter<'a, '_, T> {}
+
+impl<T: Ord> Drop for ScratchIter<'_, '_, T> {
+    fn drop(&mut self) {
+        self.scratch.restore(mem::take(&mut self.iter.prev_nodes));
+    }
+}
+
+/// Reusable stack for [`AvlTree::iter_with_scratch`]. Between traversals it only keeps the
+/// allocation and no pointers into any tree, so it does not borrow the trees it is used for.
+pub struct IterScratch<T: Ord> {
+    nodes: Vec<NonNull<AvlNode<T>>>,
+}
+
+impl<T: Ord> IterScratch<T> {
+    /// Create a new, unallocated scratch buffer
+    pub const fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    /// Lends the allocation out as the stack of an iterator borrowing a tree for `'a`.
+    fn lend<'a>(&mut self) -> Vec<&'a AvlNode<T>> {
+        let mut nodes = mem::ManuallyDrop::new(mem::take(&mut self.nodes));
+        // `NonNull<AvlNode<T>>` and `&AvlNode<T>` share their layout and the vector is empty
+        unsafe { Vec::from_raw_parts(nodes.as_mut_ptr().cast(), 0, nodes.capacity()) }
+    }
+
+    /// Takes the allocation of an iterator's stack back, dropping the nodes left on it.
+    fn restore(&mut self, nodes: Vec<&AvlNode<T>>) {
+        let mut nodes = mem::ManuallyDrop::new(nodes);
+        // references need no drop, so forgetting the pending ones is fine
+        unsafe { self.nodes = Vec::from_raw_parts(nodes.as_mut_ptr().cast(), 0, nodes.capacity()) }
+    }
+}
+
+impl<T: Ord> Default for IterScratch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// In-order iterator over the values of an AvlTree within a range, see [`AvlTree::range`].
+pub struct Range<'a, T: Ord> {
+    iter: Iter<'a, T>,
+    /// Last value within the range, `None` once it has been returned.
+    last: Option<&'a T>,
+}
+
+impl<'a, T: Ord + 'a> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let last = self.last?;
+        let value = self.iter.next()?;
+        if std::ptr::eq(value, last) {
+            self.last = None;
+        }
+        Some(value)
+    }
+}
+
+impl<'a, T: Ord + 'a> FusedIterator for Range<'a, T> {}
+
+/// Classification of a value produced by [`AvlTree::merge_join`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeSide<T> {
+    /// The value is only present in the left tree.
+    Left(T),
+    /// The value is only present in the right tree.
+    Right(T),
+    /// The value is present in both trees.
+    Both(T, T),
+}
+
+/// Sorted merge of the values of two AvlTrees.
+pub struct MergeJoin<'a, T: Ord> {
+    left: Peekable<Iter<'a, T>>,
+    right: Peekable<Iter<'a, T>>,
+    /// Last emitted value, used to check that the output is strictly increasing.
+    last: Option<&'a T>,
+}
+
+impl<'a, T: Ord + 'a> Iterator for MergeJoin<'a, T> {
+    type Item = MergeSide<&'a T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let side = match (self.left.peek(), self.right.peek()) {
+            (None, None) => None,
+            (Some(_), None) => self.left.next().map(MergeSide::Left),
+            (None, Some(_)) => self.right.next().map(MergeSide::Right),
+            (Some(left), Some(right)) => match left.cmp(right) {
+                Ordering::Less => self.left.next().map(MergeSide::Left),
+                Ordering::Greater => self.right.next().map(MergeSide::Right),
+                Ordering::Equal => Some(MergeSide::Both(
+                    self.left.next().unwrap(),
+                    self.right.next().unwrap(),
+                )),
+            },
+        };
+        if let Some(MergeSide::Left(value) | MergeSide::Right(value) | MergeSide::Both(value, _)) =
+            side
+        {
+            debug_assert!(
+                self.last.is_none_or(|last| last < value),
+                "merge emitted a duplicate or out of order value"
+            );
+            self.last = Some(value);
+        }
+        side
+    }
+}
+
+impl<'a, T: Ord + 'a> FusedIterator for MergeJoin<'a, T> {}
+
+/// In-order iterator over mutable handles to the nodes of an AvlTree.
+pub struct NodeIterMut<'a, T: Ord> {
+    prev_nodes: Vec<NonNull<AvlNode<T>>>,
+    current_tree: Link<T>,
+    marker: PhantomData<&'a mut AvlNode<T>>,
+}
+
+/// Mutable handle to a single node, which only allows changing the value but not the structure.
+pub struct NodeMut<'a, T: Ord> {
+    node: NonNull<AvlNode<T>>,
+    marker: PhantomData<&'a mut AvlNode<T>>,
+}
+
+impl<'a, T: Ord + 'a> NodeMut<'a, T> {
+    /// Returns a reference to the value stored in the node.
+    pub fn value(&self) -> &T {
+        unsafe { &(*self.node.as_ptr()).value }
+    }
+
+    /// Returns a mutable reference to the value stored in the node. The new value must keep
+    /// the same position in the order of the tree.
+    pub fn value_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.node.as_ptr()).value }
+    }
+
+    /// Returns the height of the node.
+    pub fn height(&self) -> usize {
+        unsafe { (*self.node.as_ptr()).height }
+    }
+}
+
+impl<'a, T: Ord + 'a> Iterator for NodeIterMut<'a, T> {
+    type Item = NodeMut<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            while let Some(current_node) = self.current_tree {
+                self.prev_nodes.push(current_node);
+                self.current_tree = (*current_node.as_ptr()).left;
+            }
+            let node = self.prev_nodes.pop()?;
+            // read the right subtree before handing out the node, so the handle is never aliased
+            self.current_tree = (*node.as_ptr()).right;
+            Some(NodeMut {
+                node,
+                marker: PhantomData,
+            })
+        }
+    }
+}
+
+impl<'a, T: Ord + 'a> FusedIterator for NodeIterMut<'a, T> {}
+
+/// In-order iterator over mutable references to the values of an AvlTree.
+pub struct IterMut<'a, T: Ord> {
+    prev_nodes: Vec<NonNull<AvlNode<T>>>,
+    current_tree: Link<T>,
+    marker: PhantomData<&'a mut AvlNode<T>>,
+}
+
+impl<'a, T: Ord + 'a> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            while let Some(current_node) = self.current_tree {
+                self.prev_nodes.push(current_node);
+                self.current_tree = (*current_node.as_ptr()).left;
+            }
+            let node = self.prev_nodes.pop()?;
+            // read the right subtree before handing out the value, so it is never aliased
+            self.current_tree = (*node.as_ptr()).right;
+            Some(&mut (*node.as_ptr()).value)
+        }
+    }
+}
+
+impl<'a, T: Ord + 'a> FusedIterator for IterMut<'a, T> {}
+
+/// Lookup handle returned by [`AvlTree::access_hint`].
+pub struct AccessHint<'a, T: Ord> {
+    root: &'a Link<T>,
+    // links from the root to the last visited node, each with the exclusive bounds of the
+    // values in its subtree
+    path: Vec<(&'a Link<T>, Option<&'a T>, Option<&'a T>)>,
+}
+
+impl<'a, T: Ord + 'a> AccessHint<'a, T> {
+    /// Checks if the AvlTree contains the value like [`AvlTree::contains`].
+    pub fn contains<Q: ?Sized + Comparable<T>>(&mut self, value: &Q) -> bool {
+        self.get(value).is_some()
+    }
+
+    /// Looks up `value` and returns a reference to the stored element, if any.
+    ///
+    /// ## Arguments
+    /// * `value` The value to look up, either a `T` or any query type comparable against `T`
+    pub fn get<Q: ?Sized + Comparable<T>>(&mut self, value: &Q) -> Option<&'a T> {
+        while let Some(&(_, low, high)) = self.path.last() {
+            let above_low = low.is_none_or(|low| value.compare(low) == Ordering::Greater);
+            let below_high = high.is_none_or(|high| value.compare(high) == Ordering::Less);
+            if above_low && below_high {
+                break;
+            }
+            self.path.pop();
+        }
+
+        let (mut current_tree, mut low, mut high) =
+            self.path.pop().unwrap_or((self.root, None, None));
+        while let Some(node) = current_tree {
+            let node: &'a AvlNode<T> = unsafe { &*node.as_ptr() };
+            self.path.push((current_tree, low, high));
+            match value.compare(&node.value) {
+                Ordering::Less => {
+                    high = Some(&node.value);
+                    current_tree = &node.left;
+                }
+                Ordering::Equal => return Some(&node.value),
+                Ordering::Greater => {
+                    low = Some(&node.value);
+                    current_tree = &node.right;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Fixed-size set of bits, as returned by `membership_bitset` on trees of integers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitset {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitset {
+    /// Creates a bitset of `len` unset bits.
+    fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    /// Sets the bit at `index`.
+    fn set(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Checks if the bit at `index` is set. Bits beyond the end are never set.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the number of bits in the bitset.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if the bitset has no bits at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Counts the set bits.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Implements operations that are specific to trees of primitive integers.
+macro_rules! impl_integer_tree {
+    ($($int:ty => $unsigned:ty),*) => {$(
+        impl AvlTree<$int> {
+            /// Returns the smallest integer not less than `start` that is not in the tree,
+            /// e.g. to allocate the next free id. The stored integers from `start` onwards
+            /// are dense up to a node exactly if the node's value exceeds `start` by the
+            /// number of values between them, which the cached subtree sizes tell without
+            /// visiting them. A single descent thus skips dense runs in O(height).
+            ///
+            /// ## Arguments
+            /// * `start` The smallest integer to consider
+            /// ## Panics
+            /// If every integer from `start` up to the maximum of the type is in the tree.
+            pub fn first_missing(&self, start: $int) -> $int {
+                let start_rank = self.rank(&start);
+                // number of stored integers directly following `start` without a gap
+                let mut dense = 0;
+                // number of values left of the current subtree
+                let mut offset = 0;
+                let mut current_tree = &self.root;
+                while let Some(node) = current_tree {
+                    let node = unsafe { &*node.as_ptr() };
+                    let rank = offset + link_size(&node.left);
+                    let is_dense = node.value >= start
+                        && <$unsigned>::try_from(rank - start_rank)
+                            .is_ok_and(|count| count == node.value.abs_diff(start));
+                    if node.value < start || is_dense {
+                        if is_dense {
+                            dense = rank - start_rank + 1;
+                        }
+                        offset = rank + 1;
+                        current_tree = &node.right;
+                    } else {
+                        current_tree = &node.left;
+                    }
+                }
+                let room = <$int>::MAX.abs_diff(start);
+                match <$unsigned>::try_from(dense) {
+                    // the sum fits into the type, so wrapping only undoes the cast
+                    Ok(dense) if dense <= room => start.wrapping_add(dense as $int),
+                    _ => panic!("no free integer left"),
+                }
+            }
+
+            /// Returns the distance from `value` to the next greater integer in the tree, e.g.
+            /// to detect sparse regions. A gap of 1 means that the successor directly follows.
+            ///
+            /// ## Arguments
+            /// * `value` The integer to measure from, which must be in the tree
+            /// ## Returns
+            /// `successor(value) - value`, or `None` if `value` is absent, the maximum, or
+            /// the gap overflows the type, which only happens for signed integers.
+            pub fn gap_after(&self, value: &$int) -> Option<$int> {
+                if !self.contains(value) {
+                    return None;
+                }
+                self.successor(value)
+                    .and_then(|next| next.checked_sub(*value))
+            }
+
+            /// Marks which integers of the range `lo..=hi` are in the tree. Bit `i` of the
+            /// result is set if `lo + i` is present. Only the values within the range are
+            /// visited, in a single in-order pass.
+            ///
+            /// ## Arguments
+            /// * `lo` The first integer of the range
+            /// * `hi` The last integer of the range, the result is empty if `hi < lo`
+            /// ## Panics
+            /// If the range has more than `usize::MAX` integers.
+            pub fn membership_bitset(&self, lo: $int, hi: $int) -> Bitset {
+                if hi < lo {
+                    return Bitset::new(0);
+                }
+                let len = usize::try_from(hi.abs_diff(lo))
+                    .ok()
+                    .and_then(|diff| diff.checked_add(1))
+                    .expect("range too large for a bitset");
+                let mut bitset = Bitset::new(len);
+                for value in self.iter_from(&lo).take_while(|value| **value <= hi) {
+                    bitset.set(value.abs_diff(lo) as usize);
+                }
+                bitset
+            }
+
+            /// Returns up to `k` stored integers closest to `target`, ordered by their distance
+            /// to it. Of two integers at the same distance, the smaller one comes first.
+            /// Starting at the rank of `target`, two cursors move outwards and the closer of
+            /// both values is taken each step, so this runs in O(k * height).
+            ///
+            /// ## Arguments
+            /// * `target` The integer to measure the distances from, need not be in the tree
+            /// * `k` The largest number of integers to return
+            pub fn nearest_k(&self, target: &$int, k: usize) -> Vec<&$int> {
+                let mut nearest = Vec::with_capacity(k.min(self.len()));
+                let (mut below, mut above) = (self.rank(target), self.rank(target));
+                while nearest.len() < k {
+                    let lower = below.checked_sub(1).and_then(|index| self.select(index));
+                    let upper = self.select(above);
+                    match (lower, upper) {
+                        (None, None) => break,
+                        (Some(lower), Some(upper))
+                            if lower.abs_diff(*target) > upper.abs_diff(*target) =>
+                        {
+                            nearest.push(upper);
+                            above += 1;
+                        }
+                        (Some(lower), _) => {
+                            nearest.push(lower);
+                            below -= 1;
+                        }
+                        (None, Some(upper)) => {
+                            nearest.push(upper);
+                            above += 1;
+                        }
+                    }
+                }
+                nearest
+            }
+        }
+    )*};
+}
+
+impl_integer_tree!(
+    i8 => u8,
+    i16 => u16,
+    i32 => u32,
+    i64 => u64,
+    i128 => u128,
+    isize => usize,
+    u8 => u8,
+    u16 => u16,
+    u32 => u32,
+    u64 => u64,
+    u128 => u128,
+    usize => usize
+);
+
+impl<T: Ord> IntoIterator for AvlTree<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    /// Consumes the AvlTree and returns an iterator over the owned values in sorted order.
+    fn into_iter(mut self) -> Self::IntoIter {
+        self.drain_iter()
+    }
+}
+
+/// Collecting keeps the first of several equal values and drops the later ones, see
+/// [`AvlTree::from_iter_last_wins`] for the opposite.
+impl<T: Ord> FromIterator<T> for AvlTree<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut tree = Self::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<T: Ord> Extend<T> for AvlTree<T> {
+    /// Inserts every value of `iter`, dropping those already present. The values are inserted
+    /// one by one, so the tree takes the shape produced by the rotations of
+    /// [`AvlTree::insert`]. Use [`AvlTree::extend_counted`] or [`AvlTree::from_sorted`] to
+    /// build a tree from many values at once.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl<'a, T: Ord + Copy + 'a> FromIterator<&'a T> for AvlTree<T> {
+    fn from_iter<I: IntoIterator<Item = &'a T>>(iter: I) -> Self {
+        iter.into_iter().copied().collect()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Ord + serde::Serialize> serde::Serialize for AvlTree<T> {
+    /// Writes the values of the tree as a sequence in sorted order.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Ord + serde::Deserialize<'de>> serde::Deserialize<'de> for AvlTree<T> {
+    /// Reads a sequence of values and inserts them into a new tree. The shape of the tree
+    /// may differ from the serialized one, but it holds the same set of values.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<T>::deserialize(deserializer)?;
+        Ok(values.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod avl_tree_tests {
+    use super::*;
+    use itertools::Itertools;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Arc;
+
+    /// Value that counts how often it has been dropped, ordered only by its number.
+    struct Counted(u32, Arc<AtomicUsize>);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Counted {}
+
+    impl PartialOrd for Counted {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Counted {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+    }
+
+    /// Allocates a node with the given children, bypassing any balancing.
+    fn node<T: Ord>(value: T, left: Link<T>, right: Link<T>) -> Link<T> {
+        let mut node = Box::new(AvlNode {
+            value,
+            left,
+            right,
+            height: 1,
+            size: 1,
+        });
+        node.update_height();
+        NonNull::new(Box::into_raw(node))
+    }
+
+    #[test]
+    fn insert_iter() {
+        let mut tree = AvlTree::new();
+        tree.insert(4);
+        tree.insert(3);
+        tree.insert(2);
+        tree.insert(1);
+
+        for (expected, actual) in tree.iter().enumerate() {
+            assert_eq!(&((expected + 1) as i32), actual)
+        }
+    }
+
+    #[test]
+    fn node_height() {
+        let mut tree = AvlTree::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            tree.insert(rng.gen::<u32>());
+        }
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+    }
+
+    #[test]
+    fn traversal_order() {
+        let mut tree = AvlTree::new();
+        let mut expected = Vec::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let num = rng.gen::<u32>();
+            tree.insert(num);
+            expected.push(num);
+        }
+        assert!(itertools::equal(expected.iter().sorted(), tree.iter()));
+    }
+
+    #[test]
+    fn balanced_nodes() {
+        let mut tree = AvlTree::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            tree.insert(rng.gen::<u32>());
+        }
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+    }
+
+    #[test]
+    fn contains_parity() {
+        let mut tree = AvlTree::new();
+        let mut expected = BTreeSet::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let num = rng.gen::<u32>();
+            tree.insert(num);
+            expected.insert(num);
+        }
+        assert!(itertools::all(expected.iter(), |value| tree
+            .contains(value)
+            == expected.contains(value)))
+    }
+
+    #[test]
+    fn tree_length() {
+        let mut tree = AvlTree::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            tree.insert(rng.gen::<u32>());
+        }
+        assert_eq!(1000, tree.len())
+    }
+
+    #[test]
+    fn get_key_value() {
+        let tree: AvlTree<i32> = vec![1, 2, 3].into_iter().collect();
+        assert_eq!(Some((&2, &2)), tree.get_key_value(&2));
+        assert_eq!(None, tree.get_key_value(&4));
+    }
+
+    #[test]
+    fn fused_iterators() {
+        let tree: AvlTree<i32> = vec![2, 1, 3].into_iter().collect();
+        let mut iter = tree.iter();
+        assert!(itertools::equal(iter.by_ref(), &[1, 2, 3]));
+        for _ in 0..3 {
+            assert_eq!(None, iter.next());
+        }
+        let mut node_iter = tree.node_iter();
+        assert_eq!(3, node_iter.by_ref().count());
+        for _ in 0..3 {
+            assert!(node_iter.next().is_none());
+        }
+    }
+
+    #[test]
+    fn join_with() {
+        let left: AvlTree<i32> = (0..100).collect();
+        let right: AvlTree<i32> = (101..200).collect();
+        let tree = AvlTree::join_with(left, 100, right);
+        assert!(itertools::equal(tree.iter(), &(0..200).collect::<Vec<_>>()));
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+
+        let left: AvlTree<i32> = (0..3).collect();
+        let right: AvlTree<i32> = (4..200).collect();
+        let tree = AvlTree::join_with(left, 3, right);
+        assert!(itertools::equal(tree.iter(), &(0..200).collect::<Vec<_>>()));
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+    }
+
+    #[test]
+    fn contains_subtree() {
+        // 4 is the root with the subtrees 2 -> (1, 3) and 6 -> (5, 7)
+        let tree: AvlTree<i32> = (1..=7).collect();
+        let left: AvlTree<i32> = vec![2, 1, 3].into_iter().collect();
+        let right: AvlTree<i32> = vec![6, 5, 7].into_iter().collect();
+        let partial: AvlTree<i32> = vec![6, 5].into_iter().collect();
+        let missing: AvlTree<i32> = vec![8, 7].into_iter().collect();
+        assert!(tree.contains_subtree(&left));
+        assert!(tree.contains_subtree(&right));
+        assert!(tree.contains_subtree(&tree));
+        assert!(tree.contains_subtree(&AvlTree::new()));
+        assert!(!tree.contains_subtree(&partial));
+        assert!(!tree.contains_subtree(&missing));
+    }
+
+    #[test]
+    fn iter_with_size() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let root = unsafe { &(*tree.root.unwrap().as_ptr()).value };
+        for ((value, size), node) in tree.iter_with_size().zip(tree.node_iter()) {
+            if value == root {
+                assert_eq!(tree.len(), size);
+            }
+            if node.left.is_none() && node.right.is_none() {
+                assert_eq!(1, size);
+            }
+        }
+        assert!(itertools::equal(
+            tree.iter_with_size().map(|(value, _)| value),
+            tree.iter()
+        ));
+        assert_eq!(None, AvlTree::<u32>::new().iter_with_size().next());
+    }
+
+    #[test]
+    fn node_iter_mut() {
+        let mut tree: AvlTree<i32> = (0..100).collect();
+        for (index, mut node) in tree.node_iter_mut().enumerate() {
+            assert_eq!(index as i32, *node.value());
+            assert!(node.height() >= 1);
+            *node.value_mut() *= 2;
+        }
+        assert!(itertools::equal(
+            tree.iter(),
+            (0..100).map(|x| x * 2).collect::<Vec<_>>().iter()
+        ));
+        assert!(tree.contains(&42));
+        assert!(!tree.contains(&43));
+    }
+
+    #[test]
+    fn take_nodes() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut tree = AvlTree::new();
+        for i in 0..1000 {
+            tree.insert(Counted(i, drops.clone()));
+        }
+        let graveyard = tree.take_nodes();
+        assert!(tree.root.is_none());
+        assert_eq!(0, tree.len());
+        assert_eq!(0, drops.load(AtomicOrdering::SeqCst));
+
+        std::thread::spawn(move || drop(graveyard)).join().unwrap();
+        assert_eq!(1000, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn merge_join() {
+        let left: AvlTree<i32> = vec![1, 3, 5, 7].into_iter().collect();
+        let right: AvlTree<i32> = vec![3, 4, 5, 8].into_iter().collect();
+        let expected = vec![
+            MergeSide::Left(&1),
+            MergeSide::Both(&3, &3),
+            MergeSide::Right(&4),
+            MergeSide::Both(&5, &5),
+            MergeSide::Left(&7),
+            MergeSide::Right(&8),
+        ];
+        assert_eq!(expected, left.merge_join(&right).collect::<Vec<_>>());
+        assert_eq!(0, AvlTree::<i32>::new().merge_join(&AvlTree::new()).count());
+    }
+
+    #[test]
+    fn debug_output() {
+        let tree: AvlTree<i32> = vec![3, 1, 2].into_iter().collect();
+        assert_eq!("AvlTree {1, 2, 3}", format!("{:?}", tree));
+        assert_eq!("AvlTree {}", format!("{:?}", AvlTree::<i32>::new()));
+        assert!(!format!("{:#?}", tree).contains("0x"));
+        let node = tree.node_iter().next().unwrap();
+        assert_eq!("AvlNode { value: 1, height: 1 }", format!("{:?}", node));
+    }
+
+    #[test]
+    fn comparable_lookup() {
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Record {
+            id: u32,
+            name: String,
+        }
+
+        impl Comparable<Record> for u32 {
+            fn compare(&self, key: &Record) -> Ordering {
+                self.cmp(&key.id)
+            }
+        }
+
+        let tree: AvlTree<Record> = (0..10)
+            .map(|id| Record {
+                id,
+                name: format!("record-{}", id),
+            })
+            .collect();
+        assert!(tree.contains(&7));
+        assert!(!tree.contains(&10));
+        assert_eq!("record-3", tree.get_key_value(&3).unwrap().0.name);
+        assert!(tree.contains(&Record {
+            id: 4,
+            name: String::from("record-4")
+        }));
+    }
+
+    #[test]
+    fn bulk_insert_sorted() {
+        let mut rng = rand::thread_rng();
+        let mut expected = BTreeSet::new();
+        let mut tree = AvlTree::new();
+        for _ in 0..1000 {
+            let num = rng.gen_range(0..5000);
+            tree.insert(num);
+            expected.insert(num);
+        }
+        let batch: BTreeSet<i32> = (0..1000).map(|_| rng.gen_range(0..5000)).collect();
+        let new_values = batch.difference(&expected).count();
+        expected.extend(batch.iter().copied());
+
+        assert_eq!(new_values, tree.bulk_insert_sorted(batch));
+        assert!(itertools::equal(expected.iter(), tree.iter()));
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+
+        let mut tree: AvlTree<i32> = vec![5].into_iter().collect();
+        assert_eq!(2, tree.bulk_insert_sorted(vec![1, 1, 5, 7]));
+        assert!(itertools::equal(tree.iter(), &[1, 5, 7]));
+
+        // the existing nodes are kept instead of rebuilding the tree
+        let mut tree: AvlTree<i32> = (0..100).map(|value| value * 2).collect();
+        let nodes: Vec<*const AvlNode<i32>> =
+            tree.node_iter().map(|node| node as *const _).collect();
+        assert_eq!(
+            50,
+            tree.bulk_insert_sorted((0..50).map(|value| value * 4 + 1))
+        );
+        let kept: BTreeSet<*const AvlNode<i32>> =
+            tree.node_iter().map(|node| node as *const _).collect();
+        assert!(nodes.iter().all(|node| kept.contains(node)));
+
+        // values out of order are sorted instead of dropped
+        assert_eq!(3, tree.bulk_insert_sorted(vec![1001, 3, 999]));
+        assert!(tree.contains(&1001) && tree.contains(&3) && tree.contains(&999));
+        assert_eq!(Ok(()), tree.validate());
+
+        // a batch filling a single gap becomes a subtree that is joined into the tree
+        let mut tree: AvlTree<i32> = (0..100).map(|value| value * 1000).collect();
+        assert_eq!(999, tree.bulk_insert_sorted(1..1000));
+        assert!(itertools::equal(
+            tree.iter().copied(),
+            (0..1000).chain((1..100).map(|value| value * 1000))
+        ));
+        assert_eq!(Ok(()), tree.validate());
+    }
+
+    #[test]
+    fn insert_interval() {
+        let mut tree = AvlTree::new();
+        tree.insert_interval(10, 20);
+        tree.insert_interval(30, 40);
+        tree.insert_interval(50, 60);
+        assert!(itertools::equal(
+            tree.iter(),
+            &[(10, 20), (30, 40), (50, 60)]
+        ));
+
+        // overlapping both neighbours merges all three
+        tree.insert_interval(15, 35);
+        assert!(itertools::equal(tree.iter(), &[(10, 40), (50, 60)]));
+        // a shared endpoint counts as overlap, a gap does not
+        tree.insert_interval(40, 45);
+        tree.insert_interval(61, 70);
+        assert!(itertools::equal(
+            tree.iter(),
+            &[(10, 45), (50, 60), (61, 70)]
+        ));
+        // a subsumed interval changes nothing, a subsuming one swallows the others
+        tree.insert_interval(52, 55);
+        assert!(itertools::equal(
+            tree.iter(),
+            &[(10, 45), (50, 60), (61, 70)]
+        ));
+        tree.insert_interval(0, 100);
+        assert!(itertools::equal(tree.iter(), &[(0, 100)]));
+
+        let mut rng = rand::thread_rng();
+        let mut tree = AvlTree::new();
+        let mut covered = [false; 1000];
+        for _ in 0..300 {
+            let lo = rng.gen_range(0..990);
+            let hi = lo + rng.gen_range(0..10);
+            tree.insert_interval(lo, hi);
+            covered[lo..=hi].iter_mut().for_each(|point| *point = true);
+        }
+        assert_eq!(Ok(()), tree.validate());
+        assert!(tree.iter().zip(tree.iter().skip(1)).all(|(a, b)| a.1 < b.0));
+        for (point, covered) in covered.iter().enumerate() {
+            assert_eq!(*covered, tree.contains_point(&point));
+        }
+        assert!(!AvlTree::<(u32, u32)>::new().contains_point(&0));
+    }
+
+    #[test]
+    #[should_panic(expected = "the interval ends before it starts")]
+    fn insert_interval_reversed() {
+        AvlTree::new().insert_interval(5, 3);
+    }
+
+    #[test]
+    fn first_missing() {
+        let tree: AvlTree<i32> = vec![0, 1, 2, 4, 5].into_iter().collect();
+        assert_eq!(3, tree.first_missing(0));
+        assert_eq!(3, tree.first_missing(3));
+        assert_eq!(6, tree.first_missing(4));
+        assert_eq!(-1, tree.first_missing(-1));
+        assert_eq!(0, AvlTree::<u8>::new().first_missing(0));
+
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<i16> = (0..2000).map(|_| rng.gen_range(-300..300)).collect();
+        for start in -310..310 {
+            let expected = (start..).find(|value| !tree.contains(value)).unwrap();
+            assert_eq!(expected, tree.first_missing(start));
+        }
+        // dense runs reaching past the signed maximum of their distance still fit
+        let tree: AvlTree<i8> = (-128..100).collect();
+        assert_eq!(100, tree.first_missing(-128));
+        let tree: AvlTree<u8> = (0..255).collect();
+        assert_eq!(255, tree.first_missing(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "no free integer left")]
+    fn first_missing_exhausted() {
+        let tree: AvlTree<i8> = (-128..=127).collect();
+        tree.first_missing(-128);
+    }
+
+    #[test]
+    fn dotfile_themes() {
+        let tree: AvlTree<i32> = vec![2, 1, 3].into_iter().collect();
+        let light = tree.as_dotfile_themed(DotTheme::Light).unwrap();
+        let dark = tree.as_dotfile_themed(DotTheme::Dark).unwrap();
+        let print = tree.as_dotfile_themed(DotTheme::Print).unwrap();
+        assert!(light.contains("bgcolor=\"#ffffff\"") && light.contains("fillcolor=\"#dbe9f6\""));
+        assert!(dark.contains("bgcolor=\"#1e1e1e\"") && dark.contains("fillcolor=\"#3a3d41\""));
+        assert!(
+            print.contains("fillcolor=\"#ffffff\"") && print.contains("fontname=\"Times-Roman\"")
+        );
+        assert!(light != dark && dark != print && print != light);
+        assert!(!tree.as_dotfile().unwrap().contains("bgcolor"));
+        assert_eq!(
+            None,
+            AvlTree::<i32>::new().as_dotfile_themed(DotTheme::Dark)
+        );
+    }
+
+    #[test]
+    fn drain_while() {
+        let mut tree: AvlTree<i32> = (0..100).collect();
+        let drained = tree.drain_while(|value| *value < 50);
+        assert_eq!((0..50).collect::<Vec<_>>(), drained);
+        assert!(itertools::equal(
+            tree.iter(),
+            &(50..100).collect::<Vec<_>>()
+        ));
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+
+        assert_eq!(Ok(()), tree.validate());
+
+        // pred is only asked up to the first value it rejects
+        let mut asked = 0;
+        assert_eq!(
+            vec![50, 51],
+            tree.drain_while(|value| {
+                asked += 1;
+                *value < 52
+            })
+        );
+        assert_eq!(3, asked);
+        assert!(tree.drain_while(|_| false).is_empty());
+        assert_eq!(48, tree.len());
+
+        assert_eq!(48, tree.drain_while(|_| true).len());
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn count_at_depth() {
+        let tree: AvlTree<i32> = (1..=7).collect();
+        assert_eq!(1, tree.count_at_depth(0));
+        assert_eq!(2, tree.count_at_depth(1));
+        assert_eq!(4, tree.count_at_depth(2));
+        assert_eq!(0, tree.count_at_depth(3));
+        assert_eq!(0, AvlTree::<i32>::new().count_at_depth(0));
+    }
+
+    #[test]
+    fn retain_prefix() {
+        let mut tree: AvlTree<i32> = (0..100).collect();
+        tree.retain_prefix(|value| *value < 30);
+        assert!(itertools::equal(tree.iter(), &(0..30).collect::<Vec<_>>()));
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+        tree.retain_prefix(|_| true);
+        assert_eq!(30, tree.len());
+
+        // only the values on a single path from the root are looked at
+        let mut tree: AvlTree<i32> = (0..1000).collect();
+        let height = tree.height();
+        let asked = std::cell::Cell::new(0);
+        tree.retain_prefix(|value| {
+            asked.set(asked.get() + 1);
+            *value < 600
+        });
+        assert!(asked.get() <= height);
+        assert!(itertools::equal(tree.iter().copied(), 0..600));
+        assert_eq!(Ok(()), tree.validate());
+        tree.retain_prefix(|_| false);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn extend_counted() {
+        let mut tree: AvlTree<i32> = vec![1, 2, 3].into_iter().collect();
+        assert_eq!((2, 3), tree.extend_counted(vec![3, 4, 4, 5, 1]));
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3, 4, 5]));
+        assert_eq!((0, 0), tree.extend_counted(Vec::new()));
+    }
+
+    #[test]
+    fn insert_observed() {
+        let mut tree = AvlTree::new();
+        let mut rotations = Vec::new();
+        for value in [3, 1, 2] {
+            tree.insert_observed(value, |rotation| rotations.push(*rotation));
+        }
+        assert_eq!(vec![Rotation::Left, Rotation::Right], rotations);
+
+        rotations.clear();
+        for value in [4, 5] {
+            tree.insert_observed(value, |rotation| rotations.push(*rotation));
+        }
+        assert_eq!(vec![Rotation::Left], rotations);
+        assert!(!tree.insert_observed(5, |_| panic!("no rotation expected")));
+    }
+
+    #[test]
+    fn descending_tree() {
+        let mut tree = AvlTree::descending();
+        for value in [3, 9, 1, 7, 5] {
+            tree.insert(Reverse(value));
+        }
+        assert!(itertools::equal(
+            tree.iter().map(|value| value.0),
+            vec![9, 7, 5, 3, 1]
+        ));
+        assert_eq!(Some(&1), tree.logical_min());
+        assert_eq!(Some(&9), tree.logical_max());
+        assert!(tree.contains(&Reverse(7)));
+        assert_eq!(None, AvlTree::<Reverse<i32>>::descending().logical_min());
+    }
+
+    #[test]
+    fn into_merged_vec() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut rng = rand::thread_rng();
+        let mut left = AvlTree::new();
+        let mut right = AvlTree::new();
+        let mut expected = BTreeSet::new();
+        for _ in 0..500 {
+            let (l, r) = (rng.gen_range(0..1000), rng.gen_range(0..1000));
+            left.insert(Counted(l, drops.clone()));
+            right.insert(Counted(r, drops.clone()));
+            expected.insert(l);
+            expected.insert(r);
+        }
+        let merged = left.into_merged_vec(right);
+        assert!(itertools::equal(
+            expected.iter(),
+            merged.iter().map(|value| &value.0)
+        ));
+        drop(merged);
+        assert_eq!(1000, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn partially_consumed_drain() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut tree: AvlTree<Counted> = (0..100).map(|i| Counted(i, drops.clone())).collect();
+        let taken: Vec<_> = tree.drain_iter().take(40).collect();
+        assert_eq!(100 - 40, drops.load(AtomicOrdering::SeqCst));
+        drop(taken);
+        assert_eq!(100, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn pretty_print() {
+        let tree: AvlTree<i32> = (1..=7).collect();
+        let expected = "\
+4
+├── 2
+│   ├── 1
+│   └── 3
+└── 6
+    ├── 5
+    └── 7
+";
+        assert_eq!(expected, tree.pretty_print());
+
+        let mut tree = AvlTree::new();
+        for value in [2, 1, 3, 4] {
+            tree.insert(value);
+        }
+        let expected = "\
+2
+├── 1
+└── 3
+    ├── ∅
+    └── 4
+";
+        assert_eq!(expected, tree.pretty_print());
+        assert_eq!("", AvlTree::<i32>::new().pretty_print());
+    }
+
+    #[test]
+    fn insert_or_return() {
+        let mut tree = AvlTree::new();
+        assert_eq!(None, tree.insert_or_return(String::from("value")));
+        let duplicate = String::from("value");
+        let ptr = duplicate.as_ptr();
+        let returned = tree.insert_or_return(duplicate).unwrap();
+        assert_eq!("value", returned);
+        assert_eq!(ptr, returned.as_ptr());
+        assert_eq!(1, tree.len());
+    }
+
+    #[test]
+    fn remove_sorted() {
+        let mut rng = rand::thread_rng();
+        let mut expected = BTreeSet::new();
+        let mut tree = AvlTree::new();
+        for _ in 0..1000 {
+            let num = rng.gen_range(0..2000);
+            tree.insert(num);
+            expected.insert(num);
+        }
+        let mut keys: Vec<i32> = (0..500).map(|_| rng.gen_range(-100..2100)).collect();
+        keys.sort();
+        let present = keys
+            .iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|key| expected.remove(key))
+            .count();
+
+        assert_eq!(present, tree.remove_sorted(&keys));
+        assert!(itertools::equal(expected.iter(), tree.iter()));
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+        assert_eq!(0, tree.remove_sorted(&[-5, 3000]));
+    }
+
+    #[test]
+    fn into_sorted_vec_desc() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut rng = rand::thread_rng();
+        let mut expected = BTreeSet::new();
+        let mut tree = AvlTree::new();
+        for _ in 0..1000 {
+            let num = rng.gen::<u32>();
+            tree.insert(Counted(num, drops.clone()));
+            expected.insert(num);
+        }
+        let values = tree.into_sorted_vec_desc();
+        assert!(itertools::equal(
+            expected.iter().rev(),
+            values.iter().map(|value| &value.0)
+        ));
+        drop(values);
+        assert_eq!(1000, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn content_fingerprint() {
+        let ascending: AvlTree<u32> = (0..100).collect();
+        let descending: AvlTree<u32> = (0..100).rev().collect();
+        let other: AvlTree<u32> = (1..101).collect();
+        assert_eq!(
+            ascending.content_fingerprint(),
+            descending.content_fingerprint()
+        );
+        assert_ne!(ascending.content_fingerprint(), other.content_fingerprint());
+        assert_eq!(
+            0xcbf2_9ce4_8422_2325,
+            AvlTree::<u32>::new().content_fingerprint()
+        );
+    }
+
+    #[test]
+    fn has_duplicates() {
+        let mut tree = AvlTree::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            tree.insert(rng.gen_range(0..500));
+        }
+        assert!(!tree.has_duplicates());
+        assert!(!AvlTree::<i32>::new().has_duplicates());
+
+        let mut tree: AvlTree<i32> = (0..10).collect();
+        for mut node in tree.node_iter_mut().skip(5).take(1) {
+            *node.value_mut() = 4;
+        }
+        assert!(tree.has_duplicates());
+    }
+
+    #[test]
+    fn get_many_sorted() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<i32> = (0..1000).map(|_| rng.gen_range(0..2000)).collect();
+        let mut queries: Vec<i32> = (0..500).map(|_| rng.gen_range(-10..2010)).collect();
+        queries.sort();
+        let expected: Vec<_> = queries
+            .iter()
+            .map(|query| tree.get_key_value(query).map(|(value, _)| value))
+            .collect();
+        assert_eq!(expected, tree.get_many_sorted(&queries));
+    }
+
+    #[test]
+    fn partition_by() {
+        let tree: AvlTree<i32> = (0..100).collect();
+        let groups = tree.partition_by(|value| value % 3);
+        assert_eq!(3, groups.len());
+        for (key, group) in groups {
+            assert!(itertools::equal(
+                group.iter(),
+                &(0..100)
+                    .filter(|value| value % 3 == key)
+                    .collect::<Vec<_>>()
+            ));
+            assert!(itertools::all(group.node_iter(), |node| {
+                node.balance_factor().abs() < 2
+                    && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+            }));
+        }
+    }
+
+    #[test]
+    fn balance_state() {
+        let mut tree = AvlTree::from_root(node(3, node(2, node(1, None, None), None), None));
+        assert_eq!(Some(Balance::LeftHeavy), tree.balance_state(&3));
+        assert_eq!(Some(Balance::LeftHeavy), tree.balance_state(&2));
+        assert_eq!(Some(Balance::Balanced), tree.balance_state(&1));
+        assert_eq!(None, tree.balance_state(&4));
+
+        unsafe { (*tree.root.unwrap().as_ptr()).rebalance() };
+        assert_eq!(Some(Balance::Balanced), tree.balance_state(&2));
+
+        tree.insert(4);
+        tree.insert(5);
+        assert_eq!(Some(Balance::RightHeavy), tree.balance_state(&2));
+    }
+
+    #[test]
+    fn intersection_count() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let left: BTreeSet<u32> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+            let right: BTreeSet<u32> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+            let left_tree: AvlTree<u32> = left.iter().copied().collect();
+            let right_tree: AvlTree<u32> = right.iter().copied().collect();
+
+            let intersection = left.intersection(&right).count();
+            let union = left.union(&right).count();
+            assert_eq!(intersection, left_tree.intersection_count(&right_tree));
+            assert_eq!(
+                intersection as f64 / union as f64,
+                left_tree.jaccard(&right_tree)
+            );
+        }
+        assert_eq!(1.0, AvlTree::<u32>::new().jaccard(&AvlTree::new()));
+    }
+
+    #[test]
+    fn collect_references() {
+        let values: &[i32] = &[5, 3, 8, 3, 1];
+        let tree: AvlTree<i32> = values.iter().collect();
+        assert!(itertools::equal(tree.iter(), &[1, 3, 5, 8]));
+    }
+
+    #[test]
+    fn level_order_round_trip() {
+        let mut tree = AvlTree::new();
+        for value in [2, 1, 3, 4] {
+            tree.insert(value);
+        }
+        let level_order = tree.to_level_order_options();
+        assert_eq!(
+            vec![Some(2), Some(1), Some(3), None, None, None, Some(4)],
+            level_order
+        );
+        let rebuilt = AvlTree::from_level_order(level_order).unwrap();
+        assert!(AvlTree::same_shape(&tree.root, &rebuilt.root));
+
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let rebuilt = AvlTree::from_level_order(tree.to_level_order_options()).unwrap();
+        assert!(AvlTree::same_shape(&tree.root, &rebuilt.root));
+        assert!(itertools::all(
+            tree.node_iter().zip(rebuilt.node_iter()),
+            |(node, rebuilt)| node.height == rebuilt.height
+        ));
+        assert!(AvlTree::<u32>::from_level_order(Vec::new())
+            .unwrap()
+            .root
+            .is_none());
+    }
+
+    #[test]
+    fn level_order_rejects_invalid_trees() {
+        assert_eq!(
+            Err(LevelOrderError::Unordered),
+            AvlTree::from_level_order(vec![Some(2), Some(3), Some(1)])
+        );
+        assert_eq!(
+            Err(LevelOrderError::Unbalanced),
+            AvlTree::from_level_order(vec![Some(3), Some(2), None, Some(1)])
+        );
+        // the children of 1 and 3 are read, which leaves 5 without a parent
+        assert_eq!(
+            Err(LevelOrderError::TrailingEntries),
+            AvlTree::from_level_order(vec![
+                Some(2),
+                Some(1),
+                Some(3),
+                None,
+                None,
+                None,
+                None,
+                Some(5)
+            ])
+        );
+        assert_eq!(
+            Err(LevelOrderError::TrailingEntries),
+            AvlTree::from_level_order(vec![None, Some(1)])
+        );
+    }
+
+    #[test]
+    fn take_range() {
+        let mut tree: AvlTree<i32> = (0..100).collect();
+        let taken = tree.take_range(20..=40);
+        assert!(itertools::equal(
+            taken.iter(),
+            &(20..=40).collect::<Vec<_>>()
+        ));
+        assert!(itertools::equal(
+            tree.iter(),
+            &(0..20).chain(41..100).collect::<Vec<_>>()
+        ));
+        for part in [&tree, &taken] {
+            assert!(itertools::all(part.node_iter(), |node| {
+                node.balance_factor().abs() < 2
+                    && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+            }));
+        }
+
+        assert_eq!(0, tree.take_range(200..).len());
+        assert_eq!(79, tree.take_range(..).len());
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn estimate_rank() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen_range(0..10000)).collect();
+        // the skipped subtrees are disjoint, so together they hold no more than a complete tree
+        // of the same height and the estimate is off by at most half of that
+        let bound = ((1usize << tree.height()) - 1) / 2;
+        for _ in 0..1000 {
+            let value = rng.gen_range(0..10000);
+            assert!(tree.estimate_rank(&value).abs_diff(tree.rank(&value)) <= bound);
+        }
+        assert_eq!(0, tree.estimate_rank(&0));
+        assert_eq!(0, AvlTree::<u32>::new().estimate_rank(&5));
+    }
+
+    #[test]
+    fn clone_is_deep() {
+        let mut tree: AvlTree<String> = (0..100).map(|i| i.to_string()).collect();
+        let cloned = tree.clone();
+        assert!(AvlTree::same_shape(&tree.root, &cloned.root));
+        assert!(itertools::all(
+            tree.node_iter().zip(cloned.node_iter()),
+            |(node, cloned)| node.height == cloned.height && !std::ptr::eq(node, cloned)
+        ));
+        tree.insert(String::from("new"));
+        assert!(!cloned.contains("new"));
+    }
+
+    #[test]
+    fn deep_tree_without_recursion() {
+        let mut tree = AvlTree::new();
+        for value in 0..1_000_000 {
+            tree.insert(value);
+        }
+        let cloned = tree.clone();
+        assert_eq!(1_000_000, cloned.len());
+        assert!(tree.contains_subtree(&cloned));
+        assert!(itertools::all(cloned.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+
+        // a degenerate chain, as could be left behind by a balancing bug
+        let mut chain = None;
+        for value in (0..100_000).rev() {
+            chain = node(value, None, chain);
+        }
+        let chain = AvlTree::from_root(chain);
+        let cloned = chain.clone();
+        assert!(chain.contains_subtree(&cloned));
+    }
+
+    #[test]
+    fn merge_boundary_values() {
+        let left: AvlTree<i32> = vec![1, 2, 3].into_iter().collect();
+        let right: AvlTree<i32> = vec![3, 4, 5].into_iter().collect();
+        let sides: Vec<_> = left.merge_join(&right).collect();
+        assert_eq!(5, sides.len());
+        assert_eq!(MergeSide::Both(&3, &3), sides[2]);
+        assert_eq!(1, left.intersection_count(&right));
+        assert_eq!(vec![1, 2, 3, 4, 5], left.into_merged_vec(right));
+
+        let mut tree: AvlTree<i32> = vec![1, 2, 3].into_iter().collect();
+        tree.bulk_insert_sorted(vec![3, 4, 5]);
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn into_parts() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let expected = tree.clone();
+        let root = unsafe { (*tree.root.unwrap().as_ptr()).value };
+
+        let (value, left, right) = tree.into_parts();
+        assert_eq!(Some(root), value);
+        assert!(left.iter().all(|value| *value < root));
+        assert!(right.iter().all(|value| *value > root));
+        let rejoined = AvlTree::join_with(left, value.unwrap(), right);
+        assert!(AvlTree::same_shape(&expected.root, &rejoined.root));
+
+        let (value, left, right) = AvlTree::<u32>::new().into_parts();
+        assert!(value.is_none() && left.root.is_none() && right.root.is_none());
+    }
+
+    #[test]
+    fn insert_gap_batch() {
+        let mut tree: AvlTree<i32> = (0..50).chain(1000..1050).collect();
+        let batch: Vec<i32> = (100..900).collect();
+        tree.insert_gap_batch(&batch);
+        assert!(itertools::equal(
+            tree.iter(),
+            &(0..50)
+                .chain(100..900)
+                .chain(1000..1050)
+                .collect::<Vec<_>>()
+        ));
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+
+        tree.insert_gap_batch(&[60]);
+        tree.insert_gap_batch(&[-2, -1]);
+        tree.insert_gap_batch(&[]);
+        assert_eq!(903, tree.len());
+        assert!(tree.contains(&60) && tree.contains(&-2) && tree.contains(&-1));
+
+        let mut tree = AvlTree::new();
+        tree.insert_gap_batch(&[1, 2, 3]);
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3]));
+    }
+
+    #[test]
+    fn find_within_edit_distance() {
+        let words = [
+            "apple", "apply", "apt", "band", "bandana", "hand", "bond", "bind", "zebra", "",
+        ];
+        let tree: AvlTree<String> = words.iter().map(|word| word.to_string()).collect();
+
+        assert_eq!(
+            vec!["band", "bind", "bond", "hand"],
+            tree.find_within_edit_distance("band", 1)
+        );
+        assert_eq!(vec!["apple"], tree.find_within_edit_distance("aple", 1));
+        assert_eq!(
+            vec!["apple", "apply", "apt"],
+            tree.find_within_edit_distance("aple", 2)
+        );
+        assert_eq!(vec!["zebra"], tree.find_within_edit_distance("zebra", 0));
+        assert_eq!(vec![""], tree.find_within_edit_distance("x", 1));
+        assert!(tree.find_within_edit_distance("quux", 1).is_empty());
+    }
+
+    #[test]
+    fn clone_compact() {
+        // a chain is a valid search tree, but as unbalanced as it gets
+        let mut root = None;
+        for value in (0..64).rev() {
+            root = node(value, None, root);
+        }
+        let tree = AvlTree::from_root(root);
+        let compact = tree.clone_compact();
+
+        assert!(itertools::equal(tree.iter(), compact.iter()));
+        assert_eq!(64, link_height(&tree.root));
+        assert_eq!(7, link_height(&compact.root));
+        assert!(itertools::all(compact.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+
+        let balanced: AvlTree<i32> = (0..1000).collect();
+        assert!(link_height(&balanced.clone_compact().root) <= link_height(&balanced.root));
+        assert_eq!(None, AvlTree::<i32>::new().clone_compact().root);
+    }
+
+    #[test]
+    fn remove() {
+        let mut rng = rand::thread_rng();
+        let mut values: Vec<u32> = Vec::new();
+        let mut tree = AvlTree::new();
+        while values.len() < 1000 {
+            let value = rng.gen::<u32>();
+            if tree.insert(value) {
+                values.push(value);
+            }
+        }
+
+        let (removed, kept) = values.split_at(500);
+        for value in removed {
+            assert!(tree.remove(value));
+            assert!(!tree.remove(value));
+        }
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+        assert!(itertools::equal(tree.iter(), kept.iter().sorted()));
+
+        // root with two children, whose successor has a right child
+        let mut tree: AvlTree<i32> = [2, 1, 3, 4].into_iter().collect();
+        assert!(tree.remove(&2));
+        assert_eq!(3, unsafe { (*tree.root.unwrap().as_ptr()).value });
+        assert!(itertools::equal(tree.iter(), &[1, 3, 4]));
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut tree: AvlTree<Counted> = (0..10).map(|i| Counted(i, drops.clone())).collect();
+        for i in 0..10 {
+            assert!(tree.remove(&Counted(i, drops.clone())));
+        }
+        assert_eq!(None, tree.root);
+        assert_eq!(20, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut tree = AvlTree::new();
+        for i in 0..10 {
+            tree.insert_without_rebalancing(i);
+        }
+        assert_eq!(10, tree.height());
+        assert_eq!(2.5, tree.balance_quality());
+        tree.rebuild();
+        assert_eq!(4, tree.height());
+        assert_eq!(1.0, tree.balance_quality());
+        assert_eq!(Ok(()), tree.validate());
+        assert!(tree.iter().copied().eq(0..10));
+
+        let mut empty = AvlTree::<i32>::new();
+        empty.rebuild();
+        assert!(empty.is_empty());
+        assert_eq!(1.0, empty.balance_quality());
+    }
+
+    #[test]
+    fn auto_rebuild() {
+        let mut tree = AvlTree::new();
+        for i in 0..16 {
+            tree.insert_without_rebalancing(i);
+        }
+        tree.set_auto_rebuild(1.5);
+        assert!(tree.remove(&15));
+        assert_eq!(4, tree.height());
+        assert_eq!(Ok(()), tree.validate());
+
+        let mut tree: AvlTree<u32> = (0..4096).collect();
+        let mut values = tree.iter().copied().collect_vec();
+        values.shuffle(&mut rand::thread_rng());
+        tree.set_auto_rebuild(1.1);
+        for (removed, value) in values[..4000].iter().enumerate() {
+            assert!(tree.remove(value));
+            assert!(
+                tree.balance_quality() <= 1.1,
+                "after {} removals",
+                removed + 1
+            );
+        }
+        assert_eq!(96, tree.len());
+        assert_eq!(Ok(()), tree.validate());
+    }
+
+    #[test]
+    fn remove_root_with_two_children() {
+        let value = |link: Link<i32>| link.map(|node| unsafe { (*node.as_ptr()).value });
+        let child = |link: Link<i32>, right: bool| {
+            link.and_then(|node| unsafe {
+                if right {
+                    (*node.as_ptr()).right
+                } else {
+                    (*node.as_ptr()).left
+                }
+            })
+        };
+
+        // 4 -> (2 -> (1, 3), 6 -> (5, 7)), the successor 5 is a leaf
+        let mut tree: AvlTree<i32> = [4, 2, 6, 1, 3, 5, 7].into_iter().collect();
+        assert!(tree.remove(&4));
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3, 5, 6, 7]));
+        assert_eq!(Some(5), value(tree.root));
+        let right = child(tree.root, true);
+        assert_eq!(Some(6), value(right));
+        assert_eq!(None, child(right, false));
+        assert_eq!(Some(7), value(child(right, true)));
+        assert_eq!(3, tree.height());
+        assert_eq!(Ok(()), tree.validate());
+
+        // 4 -> (2 -> 1, 7 -> (5 -> 6, 8)), the successor 5 hands its right child to 7
+        let mut tree: AvlTree<i32> = [4, 2, 7, 1, 5, 8, 6].into_iter().collect();
+        assert!(tree.remove(&4));
+        assert!(itertools::equal(tree.iter(), &[1, 2, 5, 6, 7, 8]));
+        assert_eq!(Some(5), value(tree.root));
+        let right = child(tree.root, true);
+        assert_eq!(Some(7), value(right));
+        assert_eq!(Some(6), value(child(right, false)));
+        assert_eq!(Some(8), value(child(right, true)));
+        assert_eq!(3, tree.height());
+        assert_eq!(Ok(()), tree.validate());
+    }
+
+    #[test]
+    fn snapshot_iter() {
+        let mut tree: AvlTree<i32> = (0..10).collect();
+        let snapshot = tree.snapshot_iter();
+        for value in snapshot.clone() {
+            tree.remove(&value);
+            tree.insert(value + 100);
+        }
+        tree.insert(-1);
+
+        assert!(itertools::equal(snapshot, 0..10));
+        assert!(itertools::equal(
+            tree.iter().copied(),
+            (-1..0).chain(100..110)
+        ));
+    }
+
+    #[test]
+    fn take() {
+        let mut tree: AvlTree<String> = ["delta", "bravo", "foxtrot", "alpha", "charlie", "echo"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+
+        // "delta" is the root and has two children, its successor "echo" takes its place
+        assert_eq!(Some("delta".to_string()), tree.take("delta"));
+        assert_eq!(None, tree.take("delta"));
+        assert_eq!(Some("alpha".to_string()), tree.take(&"alpha".to_string()));
+        assert!(itertools::equal(
+            tree.iter().map(String::as_str),
+            ["bravo", "charlie", "echo", "foxtrot"]
+        ));
+    }
+
+    #[test]
+    fn access_hint() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..2000).map(|_| rng.gen_range(0..10_000)).collect();
+        let mut hint = tree.access_hint();
+
+        let mut query = 5000u32;
+        for _ in 0..5000 {
+            // mostly small steps with occasional jumps across the tree
+            query = if rng.gen_ratio(1, 50) {
+                rng.gen_range(0..10_000)
+            } else {
+                query.saturating_add_signed(rng.gen_range(-3..=3))
+            };
+            assert_eq!(tree.contains(&query), hint.contains(&query));
+            if let Some(found) = hint.get(&query) {
+                assert!(std::ptr::eq(found, tree.get_key_value(&query).unwrap().0));
+            }
+        }
+
+        let empty = AvlTree::<u32>::new();
+        assert_eq!(None, empty.access_hint().get(&1));
+    }
+
+    #[test]
+    fn is_empty() {
+        let mut tree = AvlTree::new();
+        assert!(tree.is_empty());
+        tree.insert(1);
+        assert!(!tree.is_empty());
+        tree.remove(&1);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn dotfile_highlight_violations() {
+        // only the root is unbalanced, its left subtree is two levels higher than the right one
+        let root = node(5, node(3, node(2, None, None), node(4, None, None)), None);
+        let tree = AvlTree::from_root(root);
+        let dotfile = tree.dotfile_highlight_violations().unwrap();
+
+        let highlighted: Vec<&str> = dotfile
+            .lines()
+            .filter(|line| line.contains("red"))
+            .collect();
+        assert_eq!(1, highlighted.len());
+        assert!(highlighted[0].trim_start().starts_with("5["));
+        assert!(!tree.as_dotfile().unwrap().contains("red"));
+
+        // the highlighting combines with a theme
+        let themed = tree.as_dotfile_styled(Some(DotTheme::Dark), true).unwrap();
+        assert!(themed.contains("bgcolor=\"#1e1e1e\""));
+        assert_eq!(
+            1,
+            themed.lines().filter(|line| line.contains("red")).count()
+        );
+        assert_eq!(tree.as_dotfile(), tree.as_dotfile_styled(None, false));
+
+        // inserting without rotations degenerates sorted values into a list
+        let mut chain = AvlTree::new();
+        for value in 0..4 {
+            assert!(chain.insert_without_rebalancing(value));
+        }
+        assert!(!chain.insert_without_rebalancing(2));
+        assert_eq!(4, chain.height());
+        assert!(itertools::equal(chain.iter(), &[0, 1, 2, 3]));
+        assert!(matches!(
+            chain.validate(),
+            Err(Violation::Unbalanced { .. })
+        ));
+        let dotfile = chain.dotfile_highlight_violations().unwrap();
+        assert_eq!(
+            2,
+            dotfile.lines().filter(|line| line.contains("red")).count()
+        );
+
+        let balanced: AvlTree<i32> = (0..100).collect();
+        assert!(!balanced
+            .dotfile_highlight_violations()
+            .unwrap()
+            .contains("red"));
+    }
+
+    #[test]
+    fn len_tracks_subtree_sizes() {
+        let mut rng = rand::thread_rng();
+        let mut tree = AvlTree::new();
+        for _ in 0..2000 {
+            tree.insert(rng.gen_range(0..1000u32));
+            assert_eq!(tree.iter().count(), tree.len());
+        }
+        for _ in 0..1000 {
+            tree.remove(&rng.gen_range(0..1000u32));
+            assert_eq!(tree.iter().count(), tree.len());
+        }
+        assert!(itertools::all(tree.node_iter(), |node| {
+            node.size == 1 + link_size(&node.left) + link_size(&node.right)
+        }));
+
+        let joined = AvlTree::join_with(tree.clone(), 5000, (6000..6100).collect());
+        assert_eq!(tree.len() + 101, joined.len());
+        assert_eq!(tree.len(), tree.clone().len());
+        assert_eq!(0, AvlTree::<u32>::new().len());
+    }
+
+    #[test]
+    fn empty_const() {
+        const EMPTY_TREE: AvlTree<i32> = AvlTree::new();
+        assert!(EMPTY_TREE.is_empty());
+
+        let mut trees = [AvlTree::<i32>::EMPTY; 4];
+        for (index, tree) in trees.iter_mut().enumerate() {
+            tree.extend_counted(0..index as i32);
+        }
+        assert!(itertools::equal(trees.iter().map(AvlTree::len), 0..4));
+    }
+
+    #[test]
+    fn select() {
+        let mut values: Vec<u32> = (0..1000).collect();
+        values.shuffle(&mut rand::thread_rng());
+        let tree: AvlTree<u32> = values.into_iter().collect();
+
+        for k in 0..1000 {
+            assert_eq!(Some(&(k as u32)), tree.select(k));
+        }
+        assert_eq!(None, tree.select(1000));
+        assert_eq!(None, AvlTree::<u32>::new().select(0));
+    }
+
+    #[test]
+    fn join() {
+        let lower: AvlTree<i32> = (0..10).collect();
+        let upper: AvlTree<i32> = (10..1000).collect();
+        let joined = lower.join(upper);
+        assert!(itertools::equal(joined.iter().copied(), 0..1000));
+        assert_eq!(1000, joined.len());
+        assert!(itertools::all(joined.node_iter(), |node| {
+            node.balance_factor().abs() < 2
+                && node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
+        }));
+
+        let joined = unsafe { AvlTree::new().join_unchecked(joined) };
+        let joined = joined.join(AvlTree::new());
+        assert_eq!(1000, joined.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "does not lie strictly above")]
+    fn join_overlapping() {
+        let lower: AvlTree<i32> = (0..10).collect();
+        lower.join((9..20).collect());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not lie strictly above")]
+    fn join_unchecked_overlapping() {
+        let lower: AvlTree<i32> = (0..10).collect();
+        let _ = unsafe { lower.join_unchecked((5..20).collect()) };
+    }
+
+    #[test]
+    fn rank() {
+        let tree: AvlTree<u32> = (0..500).map(|value| value * 2).collect();
+        for k in 0..tree.len() {
+            assert_eq!(k, tree.rank(tree.select(k).unwrap()));
+        }
+        // odd values are absent, but rank the same as the stored value following them
+        for k in 1..tree.len() {
+            assert_eq!(k, tree.rank(&(k as u32 * 2 - 1)));
+        }
+        assert_eq!(250, tree.rank(&499));
+        assert_eq!(0, tree.rank(&0));
+        assert_eq!(tree.len(), tree.rank(&u32::MAX));
+        assert_eq!(0, AvlTree::<u32>::new().rank(&5));
+    }
+
+    #[test]
+    fn nearest_k() {
+        let tree: AvlTree<i32> = (0..=100).step_by(10).collect();
+        assert_eq!(vec![&50, &40, &60], tree.nearest_k(&50, 3));
+        assert_eq!(vec![&40, &50, &30, &60], tree.nearest_k(&44, 4));
+        assert_eq!(vec![&100, &90], tree.nearest_k(&1000, 2));
+        assert_eq!(vec![&0, &10], tree.nearest_k(&-5, 2));
+        assert_eq!(11, tree.nearest_k(&50, 20).len());
+        assert!(tree.nearest_k(&50, 0).is_empty());
+
+        let tree: AvlTree<u8> = [0, 255].into_iter().collect();
+        assert_eq!(vec![&255, &0], tree.nearest_k(&200, 5));
+    }
+
+    #[test]
+    fn into_iter() {
+        let mut rng = rand::thread_rng();
+        let values: BTreeSet<u32> = (0..1000).map(|_| rng.gen()).collect();
+        let tree: AvlTree<u32> = values.iter().copied().collect();
+        assert!(itertools::equal(tree.into_iter(), values));
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let tree: AvlTree<Counted> = (0..10).map(|i| Counted(i, drops.clone())).collect();
+        let mut iter = tree.into_iter();
+        assert_eq!(0, iter.next().unwrap().0);
+        assert_eq!(1, drops.load(AtomicOrdering::SeqCst));
+        drop(iter);
+        assert_eq!(10, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn into_iter_double_ended() {
+        let mut rng = rand::thread_rng();
+        let values: BTreeSet<u32> = (0..1000).map(|_| rng.gen()).collect();
+        let tree: AvlTree<u32> = values.iter().copied().collect();
+        assert!(itertools::equal(
+            tree.into_iter().rev(),
+            values.iter().rev().copied()
+        ));
+
+        let tree: AvlTree<u32> = (0..100).collect();
+        let mut iter = tree.into_iter();
+        assert_eq!(100, iter.len());
+        let mut front = 0;
+        let mut back = 100;
+        while iter.len() > 0 {
+            if rng.gen() {
+                assert_eq!(Some(front), iter.next());
+                front += 1;
+            } else {
+                back -= 1;
+                assert_eq!(Some(back), iter.next_back());
+            }
+            assert_eq!(back - front, iter.len() as u32);
+        }
+        assert_eq!(None, iter.next());
+        assert_eq!(None, iter.next_back());
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let tree: AvlTree<Counted> = (0..10).map(|i| Counted(i, drops.clone())).collect();
+        let mut iter = tree.into_iter();
+        assert_eq!(9, iter.next_back().unwrap().0);
+        assert_eq!(0, iter.next().unwrap().0);
+        assert_eq!(8, iter.next_back().unwrap().0);
+        assert_eq!(3, drops.load(AtomicOrdering::SeqCst));
+        assert_eq!(7, iter.len());
+        drop(iter);
+        assert_eq!(10, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn min_max() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        assert_eq!(tree.iter().min(), tree.min());
+        assert_eq!(tree.iter().max(), tree.max());
+
+        let empty = AvlTree::<u32>::new();
+        assert_eq!(None, empty.min());
+        assert_eq!(None, empty.max());
+    }
+
+    #[test]
+    fn observer() {
+        #[derive(Debug, PartialEq)]
+        enum Recorded {
+            Inserted(i32),
+            Removed(i32),
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut tree = AvlTree::new();
+        let recorder = events.clone();
+        tree.set_observer(Box::new(move |event: &Event<'_, i32>| {
+            recorder.borrow_mut().push(match event {
+                Event::Inserted(value) => Recorded::Inserted(**value),
+                Event::Removed(value) => Recorded::Removed(*value),
+            })
+        }));
+
+        for value in [3, 1, 2, 3] {
+            tree.insert(value);
+        }
+        assert_eq!(Some(3), tree.insert_or_return(3));
+        assert!(tree.remove(&1));
+        assert!(!tree.remove(&1));
+        assert_eq!(Some(2), tree.take(&2));
+        tree.insert_gap_batch(&[10, 11]);
+        tree.insert(12);
+
+        assert_eq!(
+            vec![
+                Recorded::Inserted(3),
+                Recorded::Inserted(1),
+                Recorded::Inserted(2),
+                Recorded::Removed(1),
+                Recorded::Removed(2),
+                Recorded::Inserted(12),
+            ],
+            events.take()
+        );
+    }
+
+    #[test]
+    fn membership_bitset() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<i32> = (0..300).map(|_| rng.gen_range(-500..500)).collect();
+        let bitset = tree.membership_bitset(-200, 200);
+        assert_eq!(401, bitset.len());
+        for (index, value) in (-200..=200).enumerate() {
+            assert_eq!(tree.contains(&value), bitset.get(index));
+        }
+        assert!(!bitset.get(401));
+        assert_eq!(
+            tree.iter()
+                .filter(|value| (-200..=200).contains(*value))
+                .count(),
+            bitset.count_ones()
+        );
+
+        let tree: AvlTree<i8> = [i8::MIN, 0, i8::MAX].into_iter().collect();
+        let bitset = tree.membership_bitset(i8::MIN, i8::MAX);
+        assert_eq!(256, bitset.len());
+        assert!(bitset.get(0) && bitset.get(128) && bitset.get(255));
+        assert_eq!(3, bitset.count_ones());
+        assert!(tree.membership_bitset(1, 0).is_empty());
+    }
+
+    #[test]
+    fn pop_min_max() {
+        let mut rng = rand::thread_rng();
+        let mut tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let len = tree.len();
+        let mut popped = Vec::new();
+        while let Some(min) = tree.pop_min() {
+            popped.push(min);
+            assert!(itertools::all(tree.node_iter(), |node| {
+                node.balance_factor().abs() < 2
+            }));
+            if let Some(max) = tree.pop_max() {
+                assert!(tree.max().is_none_or(|rest| *rest < max));
             }
         }
+        assert!(popped.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(len.div_ceil(2), popped.len());
+        assert!(tree.is_empty());
+
+        let mut tree: AvlTree<u32> = [7].into_iter().collect();
+        assert_eq!(Some(7), tree.pop_max());
+        assert_eq!(None, tree.root);
+        assert_eq!(None, tree.pop_max());
+        assert_eq!(None, tree.pop_min());
     }
-}
 
-impl<'a, T: Ord + Display + 'a> Iterator for Iter<'a, T> {
-    type Item = &'a T;
+    #[test]
+    fn btreeset_round_trip() {
+        let mut rng = rand::thread_rng();
+        let set: BTreeSet<u32> = (0..1000).map(|_| rng.gen()).collect();
+        let tree = AvlTree::from_btreeset(set.clone());
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match *self.current_tree {
-                None => match self.prev_nodes.pop() {
-                    None => return None,
-                    Some(ref prev_node) => {
-                        self.current_tree = &prev_node.right;
-                        return Some(&prev_node.value);
-                    }
-                },
-                Some(ref current_node) => unsafe {
-                    if (*current_node.as_ptr()).left.is_some() {
-                        self.prev_nodes.push(&(*current_node.as_ptr()));
-                        self.current_tree = &(*current_node.as_ptr()).left;
+        // a build without rotations yields the minimal height for the number of values
+        let minimal_height = (usize::BITS - set.len().leading_zeros()) as usize;
+        assert_eq!(minimal_height, link_height(&tree.root));
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+        assert_eq!(set, tree.into_btreeset());
 
-                        continue;
-                    }
-                    if (*current_node.as_ptr()).right.is_some() {
-                        self.current_tree = &(*current_node.as_ptr()).right;
-                        return Some(&(*current_node.as_ptr()).value);
-                    }
-                    self.current_tree = &None;
-                    return Some(&(*current_node.as_ptr()).value);
-                },
-            }
+        assert!(AvlTree::from_btreeset(BTreeSet::<u32>::new()).is_empty());
+    }
+
+    #[test]
+    fn floor_ceil() {
+        let tree: AvlTree<i32> = [10, 20, 30, 40, 50, 60, 70].into_iter().collect();
+
+        assert_eq!(Some(&30), tree.floor(&30));
+        assert_eq!(Some(&30), tree.ceil(&30));
+        assert_eq!(Some(&30), tree.floor(&35));
+        assert_eq!(Some(&40), tree.ceil(&35));
+        assert_eq!(Some(&10), tree.floor(&19));
+        assert_eq!(Some(&20), tree.ceil(&11));
+
+        assert_eq!(None, tree.floor(&9));
+        assert_eq!(Some(&10), tree.ceil(&9));
+        assert_eq!(Some(&70), tree.floor(&71));
+        assert_eq!(None, tree.ceil(&71));
+
+        let empty = AvlTree::<i32>::new();
+        assert_eq!(None, empty.floor(&0));
+        assert_eq!(None, empty.ceil(&0));
+    }
+
+    #[test]
+    fn predecessor_successor() {
+        let tree: AvlTree<i32> = (0..1000).collect();
+        for k in 1..999 {
+            assert_eq!(Some(&(k + 1)), tree.successor(&k));
+            assert_eq!(Some(&(k - 1)), tree.predecessor(&k));
         }
+        assert_eq!(None, tree.predecessor(&0));
+        assert_eq!(None, tree.successor(&999));
+        assert_eq!(Some(&999), tree.predecessor(&5000));
+        assert_eq!(Some(&0), tree.successor(&-5));
+
+        let sparse: AvlTree<i32> = [10, 20, 30].into_iter().collect();
+        assert_eq!(Some(&10), sparse.predecessor(&15));
+        assert_eq!(Some(&20), sparse.successor(&15));
     }
-}
 
-impl<T: Ord + Display> FromIterator<T> for AvlTree<T> {
-    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
-        let mut tree = Self::new();
-        for value in iter {
-            tree.insert(value);
+    #[test]
+    fn retain_indexed() {
+        let mut tree: AvlTree<i32> = (0..10).map(|value| value * 3).collect();
+        tree.retain_indexed(|index, _| index % 2 == 0);
+        assert!(itertools::equal(tree.iter(), &[0, 6, 12, 18, 24]));
+        assert!(itertools::all(tree.node_iter(), |node| node
+            .balance_factor()
+            .abs()
+            < 2));
+
+        tree.retain_indexed(|index, value| index > 0 && *value != 18);
+        assert!(itertools::equal(tree.iter(), &[6, 12, 24]));
+        tree.retain_indexed(|_, _| false);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn validate() {
+        let mut rng = rand::thread_rng();
+        let mut tree: AvlTree<u32> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+        for _ in 0..200 {
+            tree.remove(&rng.gen_range(0..1000));
         }
-        tree
+        assert_eq!(Ok(()), tree.validate());
+        debug_assert_tree(&tree);
+
+        let unordered = AvlTree::from_root(node(2, node(3, None, None), None));
+        assert_eq!(
+            Err(Violation::Unordered {
+                previous: &3,
+                value: &2
+            }),
+            unordered.validate()
+        );
+        let unbalanced = AvlTree::from_root(node(3, node(2, node(1, None, None), None), None));
+        assert_eq!(
+            Err(Violation::Unbalanced {
+                value: &3,
+                left_height: 2,
+                right_height: 0
+            }),
+            unbalanced.validate()
+        );
+        let stale = AvlTree::from_root(node(2, node(1, None, None), None));
+        unsafe { (*stale.root.unwrap().as_ptr()).height = 5 };
+        assert_eq!(
+            Err(Violation::StaleHeight {
+                value: &2,
+                cached: 5,
+                actual: 2
+            }),
+            stale.validate()
+        );
+        unsafe { (*stale.root.unwrap().as_ptr()).height = 2 };
+        unsafe { (*stale.root.unwrap().as_ptr()).size = 1 };
+        assert!(matches!(
+            stale.validate(),
+            Err(Violation::StaleSize {
+                cached: 1,
+                actual: 2,
+                ..
+            })
+        ));
     }
-}
 
-#[cfg(test)]
-mod avl_tree_tests {
-    use super::*;
-    use itertools::Itertools;
-    use rand::Rng;
-    use std::collections::BTreeSet;
+    #[test]
+    #[should_panic(expected = "invalid AvlTree: Unordered")]
+    fn debug_assert_tree_corrupted() {
+        let mut tree: AvlTree<i32> = (0..10).collect();
+        for mut node in tree.node_iter_mut() {
+            *node.value_mut() = -*node.value();
+        }
+        debug_assert_tree(&tree);
+    }
 
     #[test]
-    fn insert_iter() {
+    fn range() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<i32> = (0..300).map(|_| rng.gen_range(0..600)).collect();
+        for _ in 0..200 {
+            let (start, end) = (rng.gen_range(-10..610), rng.gen_range(-10..610));
+            assert!(itertools::equal(
+                tree.range(start..end),
+                tree.iter().filter(|value| (start..end).contains(*value))
+            ));
+            assert!(itertools::equal(
+                tree.range(start..=end),
+                tree.iter().filter(|value| (start..=end).contains(*value))
+            ));
+            let excluded = (Bound::Excluded(start), Bound::Included(end));
+            assert!(itertools::equal(
+                tree.range(excluded),
+                tree.iter().filter(|value| excluded.contains(*value))
+            ));
+        }
+        assert!(itertools::equal(tree.range(..), tree.iter()));
+        assert!(itertools::equal(
+            tree.range(300..),
+            tree.iter().filter(|value| **value >= 300)
+        ));
+
+        let sparse: AvlTree<i32> = [10, 20, 30].into_iter().collect();
+        assert_eq!(0, sparse.range(11..20).count());
+        assert_eq!(0, sparse.range(20..20).count());
+        assert_eq!(
+            0,
+            sparse
+                .range((Bound::Included(25), Bound::Included(15)))
+                .count()
+        );
+        assert!(itertools::equal(sparse.range(20..=20), &[20]));
+        assert_eq!(0, AvlTree::<i32>::new().range(..).count());
+    }
+
+    #[test]
+    fn split_after() {
+        let mut tree: AvlTree<i32> = (0..100).map(|value| value * 2).collect();
+        let upper = tree.split_after(&100);
+        assert!(itertools::equal(tree.iter().copied(), (0..=100).step_by(2)));
+        assert!(itertools::equal(
+            upper.iter().copied(),
+            (102..200).step_by(2)
+        ));
+        assert_eq!(Ok(()), tree.validate());
+        assert_eq!(Ok(()), upper.validate());
+
+        // an absent key splits at the gap it would fall into
+        let upper = tree.split_after(&51);
+        assert_eq!(Some(&50), tree.max());
+        assert_eq!(Some(&52), upper.min());
+        assert_eq!(51, tree.len() + upper.len());
+
+        assert!(tree.split_after(&1000).is_empty());
+        let all = tree.split_after(&-1);
+        assert!(tree.is_empty());
+        assert_eq!(26, all.len());
+    }
+
+    #[test]
+    fn iter_rev() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let mut forward: Vec<_> = tree.iter().collect();
+        let backward: Vec<_> = tree.iter().rev().collect();
+        forward.reverse();
+        assert_eq!(forward, backward);
+
+        // both ends meet in the middle without repeating a value
+        let tree: AvlTree<i32> = (0..7).collect();
+        let mut iter = tree.iter();
+        assert_eq!(7, iter.len());
+        assert_eq!(Some(&0), iter.next());
+        assert_eq!(Some(&6), iter.next_back());
+        assert_eq!(Some(&5), iter.next_back());
+        assert!(itertools::equal(iter.by_ref(), &[1, 2, 3, 4]));
+        assert_eq!(None, iter.next_back());
+        assert_eq!(None, iter.next());
+
+        assert!(itertools::equal(tree.iter_from(&3).rev(), &[6, 5, 4, 3]));
+        assert_eq!(None, AvlTree::<i32>::new().iter().next_back());
+    }
+
+    #[test]
+    fn iter_with_scratch() {
+        let mut rng = rand::thread_rng();
+        let mut tree: AvlTree<u32> = (0..1000).map(|_| rng.gen::<u32>()).collect();
+        let mut scratch = IterScratch::new();
+        assert!(itertools::equal(
+            tree.iter_with_scratch(&mut scratch),
+            tree.iter()
+        ));
+        assert!(scratch.nodes.is_empty() && scratch.nodes.capacity() > 0);
+
+        // the buffer does not borrow the tree, so it survives modifications and other trees
+        for _ in 0..100 {
+            tree.insert(rng.gen());
+            tree.pop_min();
+            assert!(itertools::equal(
+                tree.iter_with_scratch(&mut scratch),
+                tree.iter()
+            ));
+        }
+        let other: AvlTree<u32> = (0..10).collect();
+        let mut iter = other.iter_with_scratch(&mut scratch);
+        assert_eq!(Some(&0), iter.next());
+        drop(iter);
+        assert!(scratch.nodes.is_empty());
+    }
+
+    #[test]
+    fn iter_mut() {
+        let mut rng = rand::thread_rng();
+        let mut tree: AvlTree<u32> = (0..1000).map(|_| rng.gen_range(0..100_000)).collect();
+        let expected: Vec<u32> = tree.iter().map(|value| value * 2).collect();
+        for value in tree.iter_mut() {
+            *value *= 2;
+        }
+        assert!(itertools::equal(tree.iter(), &expected));
+        assert_eq!(Ok(()), tree.validate());
+        assert!(tree.contains(&expected[500]));
+        assert_eq!(None, AvlTree::<u32>::new().iter_mut().next());
+    }
+
+    #[test]
+    fn any_in_ranges() {
+        let mut rng = rand::thread_rng();
+        let tree: AvlTree<u32> = (0..100).map(|_| rng.gen_range(0..2000)).collect();
+        let ranges: Vec<(u32, u32)> = (0..200).map(|i| (i * 10, i * 10 + 4)).collect();
+        let expected: Vec<bool> = ranges
+            .iter()
+            .map(|(lo, hi)| tree.range(lo..=hi).next().is_some())
+            .collect();
+        assert_eq!(expected, tree.any_in_ranges(&ranges));
+
+        let tree: AvlTree<u32> = [5, 20].into_iter().collect();
+        assert_eq!(
+            vec![false, true, false, true, false],
+            tree.any_in_ranges(&[(0, 4), (5, 5), (6, 19), (15, 25), (21, 100)])
+        );
+        assert!(tree.any_in_ranges(&[]).is_empty());
+    }
+
+    #[test]
+    fn get() {
+        /// Interned record whose ordering only considers the id.
+        #[derive(Debug)]
+        struct Record {
+            id: u32,
+            name: &'static str,
+        }
+        impl PartialEq for Record {
+            fn eq(&self, other: &Self) -> bool {
+                self.id == other.id
+            }
+        }
+        impl Eq for Record {}
+        impl PartialOrd for Record {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Record {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.id.cmp(&other.id)
+            }
+        }
+
         let mut tree = AvlTree::new();
-        tree.insert(4);
-        tree.insert(3);
-        tree.insert(2);
-        tree.insert(1);
+        tree.insert(Record { id: 1, name: "one" });
+        tree.insert(Record { id: 2, name: "two" });
+        tree.insert(Record {
+            id: 3,
+            name: "three",
+        });
 
-        for (expected, actual) in tree.iter().enumerate() {
-            assert_eq!(&((expected + 1) as i32), actual)
+        let probe = Record { id: 2, name: "" };
+        let stored = tree.get(&probe).unwrap();
+        assert_eq!("two", stored.name);
+        assert!(std::ptr::eq(stored, tree.get_key_value(&probe).unwrap().0));
+        assert!(tree
+            .get(&Record {
+                id: 4,
+                name: "four"
+            })
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "cached height is out of date")]
+    fn iter_detects_stale_height() {
+        let tree: AvlTree<i32> = (0..10).collect();
+        unsafe { (*tree.root.unwrap().as_ptr()).height += 1 };
+        tree.iter().for_each(drop);
+    }
+
+    #[test]
+    fn borrowed_lookup() {
+        let mut tree: AvlTree<String> = ["alpha", "bravo", "charlie"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+
+        assert!(tree.contains("bravo"));
+        assert!(!tree.contains("delta"));
+        assert_eq!(Some(&"charlie".to_string()), tree.get("charlie"));
+        assert_eq!(None, tree.get("echo"));
+        assert_eq!(1, tree.rank("bravo"));
+        assert_eq!(Some(&"alpha".to_string()), tree.floor("apple"));
+        assert!(tree.remove("alpha"));
+        assert!(!tree.contains("alpha"));
+    }
+
+    #[test]
+    fn remove_root() {
+        let mut tree: AvlTree<i32> = (1..=7).collect();
+        assert_eq!(Some(4), tree.remove_root());
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3, 5, 6, 7]));
+        assert_eq!(Ok(()), tree.validate());
+        assert_eq!(5, unsafe { (*tree.root.unwrap().as_ptr()).value });
+
+        // a root with a single child hands the root over to that child
+        let mut tree: AvlTree<i32> = [1, 2].into_iter().collect();
+        assert_eq!(Some(1), tree.remove_root());
+        assert_eq!(Some(2), tree.remove_root());
+        assert_eq!(None, tree.remove_root());
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn clear() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut tree: AvlTree<Counted> = (0..100).map(|i| Counted(i, drops.clone())).collect();
+        tree.clear();
+        assert!(tree.is_empty());
+        assert_eq!(0, tree.len());
+        assert_eq!(100, drops.load(AtomicOrdering::SeqCst));
+
+        tree.extend_counted((0..10).map(|i| Counted(i, drops.clone())));
+        assert_eq!(10, tree.len());
+        drop(tree);
+        assert_eq!(110, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn get_instrumented() {
+        let tree: AvlTree<i32> = (0..1000).map(|value| value * 2).collect();
+        assert_eq!(10, tree.height());
+        for value in -1..2001 {
+            let (found, comparisons) = tree.get_instrumented(&value);
+            assert_eq!(tree.get(&value), found);
+            assert!((1..=tree.height()).contains(&comparisons));
         }
+        let root = unsafe { &(*tree.root.unwrap().as_ptr()).value };
+        assert_eq!((Some(root), 1), tree.get_instrumented(root));
+
+        let empty = AvlTree::<i32>::new();
+        assert_eq!(0, empty.height());
+        assert_eq!((None, 0), empty.get_instrumented(&1));
     }
 
     #[test]
-    fn node_height() {
+    fn extend() {
+        let mut rng = rand::thread_rng();
+        let initial: Vec<u32> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+        let batch: Vec<u32> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+
+        let mut extended: AvlTree<u32> = initial.iter().collect();
+        extended.extend(batch.clone());
+        let mut inserted: AvlTree<u32> = initial.iter().collect();
+        for value in batch {
+            inserted.insert(value);
+        }
+        assert!(itertools::equal(extended.iter(), inserted.iter()));
+        assert_eq!(Ok(()), extended.validate());
+
         let mut tree = AvlTree::new();
+        tree.extend(vec![1, 2, 3]);
+        assert!(itertools::equal(tree.iter(), &[1, 2, 3]));
+    }
+
+    #[test]
+    fn into_union() {
         let mut rng = rand::thread_rng();
-        for _ in 0..1000 {
-            tree.insert(rng.gen::<u32>());
+        let left: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+        let right: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+        let union =
+            AvlTree::from_btreeset(left.clone()).into_union(AvlTree::from_btreeset(right.clone()));
+        assert!(itertools::equal(union.iter(), left.union(&right)));
+        assert_eq!(Ok(()), union.validate());
+
+        // duplicates of the right tree are dropped, every value exactly once
+        let drops = Arc::new(AtomicUsize::new(0));
+        let left: AvlTree<Counted> = (0..10).map(|i| Counted(i, drops.clone())).collect();
+        let right: AvlTree<Counted> = (5..15).map(|i| Counted(i, drops.clone())).collect();
+        let union = left.into_union(right);
+        assert_eq!(5, drops.load(AtomicOrdering::SeqCst));
+        assert_eq!(15, union.len());
+        drop(union);
+        assert_eq!(20, drops.load(AtomicOrdering::SeqCst));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let empty = AvlTree::<i32>::new();
+        assert_eq!("[]", serde_json::to_string(&empty).unwrap());
+
+        let tree: AvlTree<i32> = [5, -3, 17, 0, 8, 42, 1].into_iter().collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_eq!("[-3,0,1,5,8,17,42]", json);
+        let restored: AvlTree<i32> = serde_json::from_str(&json).unwrap();
+        assert!(itertools::equal(tree.iter(), restored.iter()));
+        assert_eq!(Ok(()), restored.validate());
+
+        // duplicates and unsorted input still form a set
+        let restored: AvlTree<i32> = serde_json::from_str("[3,1,3,2]").unwrap();
+        assert!(itertools::equal(restored.iter(), &[1, 2, 3]));
+    }
+
+    #[test]
+    fn from_sorted() {
+        for len in [0, 1, 2, 3, 7, 8, 100, 1023, 1024] {
+            let tree = AvlTree::from_sorted(0..len);
+            assert!(itertools::equal(tree.iter().copied(), 0..len));
+            assert_eq!(len as usize, tree.len());
+            assert_eq!(Ok(()), tree.validate());
+            // a perfectly balanced tree has minimal height
+            assert_eq!(
+                (usize::BITS - (len as usize).leading_zeros()) as usize,
+                tree.height()
+            );
         }
-        assert!(itertools::all(tree.node_iter(), |node| {
-            node.height == 1 + std::cmp::max(node.left_height(), node.right_height())
-        }));
     }
 
     #[test]
-    fn traversal_order() {
-        let mut tree = AvlTree::new();
-        let mut expected = Vec::new();
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not in strictly ascending order")]
+    fn from_sorted_duplicates() {
+        let _ = AvlTree::from_sorted([1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn shrink_to_max_len() {
+        let mut values: Vec<u32> = (0..100).map(|value| value * 3).collect();
+        values.shuffle(&mut rand::thread_rng());
+        let mut tree: AvlTree<u32> = values.into_iter().collect();
+        assert!(tree.shrink_to_max_len(100).is_empty());
+        let evicted = tree.shrink_to_max_len(60);
+        assert!(itertools::equal(evicted, (60..100).map(|value| value * 3)));
+        assert!(itertools::equal(
+            tree.iter().copied(),
+            (0..60).map(|value| value * 3)
+        ));
+        assert_eq!(60, tree.len());
+        assert_eq!(Ok(()), tree.validate());
+
+        assert_eq!(60, tree.shrink_to_max_len(0).len());
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn union() {
         let mut rng = rand::thread_rng();
-        for _ in 0..1000 {
-            let num = rng.gen::<u32>();
-            tree.insert(num);
-            expected.push(num);
+        let left: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+        let right: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+        let left_tree: AvlTree<u32> = left.iter().collect();
+        let right_tree: AvlTree<u32> = right.iter().collect();
+        let union = left_tree.union(&right_tree);
+        assert!(itertools::equal(union.iter(), left.union(&right)));
+        assert_eq!(Ok(()), union.validate());
+        assert!(itertools::equal(left_tree.iter(), &left));
+
+        let empty = AvlTree::new();
+        assert!(itertools::equal(left_tree.union(&empty).iter(), &left));
+        assert!(itertools::equal(empty.union(&right_tree).iter(), &right));
+        assert!(empty.union(&AvlTree::new()).is_empty());
+    }
+
+    #[test]
+    fn gap_after() {
+        let tree: AvlTree<i32> = [1, 5, 6].into_iter().collect();
+        assert_eq!(Some(4), tree.gap_after(&1));
+        assert_eq!(Some(1), tree.gap_after(&5));
+        assert_eq!(None, tree.gap_after(&6));
+        assert_eq!(None, tree.gap_after(&3));
+        assert_eq!(None, AvlTree::<u8>::new().gap_after(&0));
+        let tree: AvlTree<i8> = [-100, 27].into_iter().collect();
+        assert_eq!(Some(127), tree.gap_after(&-100));
+        // the gap exceeds i8::MAX
+        let tree: AvlTree<i8> = [-100, 100].into_iter().collect();
+        assert_eq!(None, tree.gap_after(&-100));
+        let tree: AvlTree<i64> = [i64::MIN, i64::MAX].into_iter().collect();
+        assert_eq!(None, tree.gap_after(&i64::MIN));
+    }
+
+    #[test]
+    fn intersection() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let left: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+            let right: BTreeSet<u32> = (0..200).map(|_| rng.gen_range(0..1000)).collect();
+            let left_tree: AvlTree<u32> = left.iter().collect();
+            let right_tree: AvlTree<u32> = right.iter().collect();
+            let intersection = left_tree.intersection(&right_tree);
+            assert!(itertools::equal(
+                intersection.iter(),
+                left.intersection(&right)
+            ));
+            assert!(itertools::equal(
+                right_tree.intersection(&left_tree).iter(),
+                intersection.iter()
+            ));
+            assert_eq!(Ok(()), intersection.validate());
         }
-        assert!(itertools::equal(expected.iter().sorted(), tree.iter()));
+        let tree: AvlTree<u32> = (0..10).collect();
+        assert!(tree.intersection(&AvlTree::new()).is_empty());
+        assert!(AvlTree::new().intersection(&tree).is_empty());
     }
 
     #[test]
-    fn balanced_nodes() {
-        let mut tree = AvlTree::new();
+    fn from_iter_last_wins() {
+        let (first, last) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let values = || {
+            [3, 1, 2]
+                .into_iter()
+                .map(|i| Counted(i, first.clone()))
+                .chain([2, 3, 4].into_iter().map(|i| Counted(i, last.clone())))
+        };
+        let first_wins: AvlTree<Counted> = values().collect();
+        let last_wins = AvlTree::from_iter_last_wins(values());
+        let payloads = |tree: &AvlTree<Counted>, payload: &Arc<AtomicUsize>| {
+            tree.iter()
+                .filter(|value| Arc::ptr_eq(&value.1, payload))
+                .map(|value| value.0)
+                .collect_vec()
+        };
+        assert_eq!(vec![1, 2, 3], payloads(&first_wins, &first));
+        assert_eq!(vec![4], payloads(&first_wins, &last));
+        assert_eq!(vec![1], payloads(&last_wins, &first));
+        assert_eq!(vec![2, 3, 4], payloads(&last_wins, &last));
+        assert!(last_wins.validate().is_ok());
+        // the dropped duplicates are counted by their payload
+        assert_eq!(2, last.load(AtomicOrdering::SeqCst));
+        assert_eq!(2, first.load(AtomicOrdering::SeqCst));
+    }
+
+    #[test]
+    fn difference() {
         let mut rng = rand::thread_rng();
-        for _ in 0..1000 {
-            tree.insert(rng.gen::<u32>());
+        for _ in 0..20 {
+            let left: BTreeSet<u32> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+            let right: BTreeSet<u32> = (0..200).map(|_| rng.gen_range(0..1000)).collect();
+            let left_tree: AvlTree<u32> = left.iter().collect();
+            let right_tree: AvlTree<u32> = right.iter().collect();
+            let difference = left_tree.difference(&right_tree);
+            assert!(itertools::equal(difference.iter(), left.difference(&right)));
+            assert!(itertools::equal(
+                right_tree.difference(&left_tree).iter(),
+                right.difference(&left)
+            ));
+            assert_eq!(Ok(()), difference.validate());
+            assert!(left_tree.difference(&left_tree).is_empty());
         }
-        assert!(itertools::all(tree.node_iter(), |node| node
-            .balance_factor()
-            .abs()
-            < 2));
+        let tree: AvlTree<u32> = (0..10).collect();
+        assert!(itertools::equal(
+            tree.difference(&AvlTree::new()).iter(),
+            tree.iter()
+        ));
+        assert!(AvlTree::new().difference(&tree).is_empty());
     }
 
     #[test]
-    fn contains_parity() {
+    fn content_equality() {
+        let mut ascending = AvlTree::new();
+        for value in 0..100 {
+            ascending.insert(value);
+        }
+        let mut values = (0..100).collect_vec();
+        values.shuffle(&mut rand::thread_rng());
+        let shuffled: AvlTree<u32> = values.into_iter().collect();
+        let perfect = AvlTree::from_sorted(0..100);
+        assert_ne!(
+            ascending.to_level_order_options(),
+            perfect.to_level_order_options()
+        );
+        assert_eq!(ascending, shuffled);
+        assert_eq!(ascending, perfect);
+        assert_eq!(AvlTree::<u32>::new(), AvlTree::new());
+        assert_ne!(ascending, AvlTree::from_sorted(0..99));
+        assert_ne!(ascending, AvlTree::from_sorted(1..101));
+    }
+
+    #[test]
+    fn extend_counted_builds_empty_tree() {
+        let mut rng = rand::thread_rng();
+        let values = (0..1000).map(|_| rng.gen_range(0..500)).collect_vec();
+        let expected: BTreeSet<u32> = values.iter().copied().collect();
+        let perfect_height = (usize::BITS - expected.len().leading_zeros()) as usize;
+
+        // extend inserts one by one, so the tree has the shape of repeated inserts
+        let mut tree = AvlTree::new();
+        tree.extend(values.iter().copied());
+        let mut inserted = AvlTree::new();
+        for value in &values {
+            inserted.insert(*value);
+        }
+        assert!(AvlTree::same_shape(&tree.root, &inserted.root));
+        assert!(itertools::equal(tree.iter(), &expected));
+        assert_eq!(Ok(()), tree.validate());
+
+        // a non-empty tree merges the new values by inserting them
+        let more = (0..1000).map(|_| rng.gen_range(250..750)).collect_vec();
+        tree.extend(more.iter().copied());
+        assert!(itertools::equal(
+            tree.iter(),
+            &expected
+                .iter()
+                .chain(&more)
+                .copied()
+                .collect::<BTreeSet<u32>>()
+        ));
+        assert_eq!(Ok(()), tree.validate());
+
+        let mut tree = AvlTree::new();
+        let (inserted, duplicates) = tree.extend_counted(values);
+        assert_eq!(
+            (expected.len(), 1000 - expected.len()),
+            (inserted, duplicates)
+        );
+        assert_eq!(perfect_height, tree.height());
+
+        // an observer still sees every insertion
+        let events = Rc::new(RefCell::new(0));
+        let seen = events.clone();
         let mut tree = AvlTree::new();
+        tree.set_observer(Box::new(move |_: &Event<'_, u32>| *seen.borrow_mut() += 1));
+        tree.extend([3, 1, 2, 1]);
+        assert_eq!(3, events.take());
+    }
+
+    #[test]
+    fn content_hash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |tree: &AvlTree<u32>| {
+            let mut hasher = DefaultHasher::new();
+            tree.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut ascending = AvlTree::new();
+        for value in 0..100 {
+            ascending.insert(value);
+        }
+        let perfect = AvlTree::from_sorted(0..100);
+        assert_eq!(hash(&ascending), hash(&perfect));
+        assert_ne!(hash(&ascending), hash(&AvlTree::from_sorted(0..99)));
+
+        let mut counts = HashMap::new();
+        *counts.entry(ascending).or_insert(0) += 1;
+        *counts.entry(perfect).or_insert(0) += 1;
+        assert_eq!(Some(&2), counts.get(&AvlTree::from_sorted(0..100)));
+    }
+
+    #[test]
+    fn select_range() {
+        let tree: AvlTree<u32> = (0..300).map(|value| value * 7 % 1000).collect();
+        for (start, end) in [
+            (0, 0),
+            (0, 300),
+            (100, 150),
+            (299, 300),
+            (250, 400),
+            (300, 310),
+            (50, 10),
+        ] {
+            assert!(itertools::equal(
+                tree.select_range(start, end),
+                tree.iter().skip(start).take(end.saturating_sub(start))
+            ));
+            assert!(itertools::equal(
+                tree.select_range(start, end).rev(),
+                tree.iter()
+                    .skip(start)
+                    .take(end.saturating_sub(start))
+                    .rev()
+            ));
+        }
+        // the seek only descends once from the root
+        for k in 0..tree.len() {
+            assert!(tree.iter_at_rank(k).prev_nodes.len() <= tree.height());
+        }
+        assert_eq!(None, AvlTree::<u32>::new().select_range(0, 10).next());
+    }
+
+    #[test]
+    fn strict_checks() {
+        let mut rng = rand::thread_rng();
+        let mut tree = AvlTree::with_strict_checks(true);
         let mut expected = BTreeSet::new();
+        for _ in 0..5000 {
+            let value = rng.gen_range(0..300);
+            match rng.gen_range(0..6) {
+                0 | 1 => assert_eq!(expected.insert(value), tree.insert(value)),
+                2 => assert_eq!(expected.remove(&value), tree.remove(&value)),
+                3 => assert_eq!(expected.pop_first(), tree.pop_min()),
+                4 => assert_eq!(expected.pop_last(), tree.pop_max()),
+                _ => assert_eq!(expected.take(&value), tree.take(&value)),
+            }
+        }
+        assert!(itertools::equal(tree.iter(), &expected));
+        assert!(AvlTree::<u32>::with_strict_checks(false)
+            .self_check
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "after [\"insert 5\", \"insert 3\", \"insert 8\"]")]
+    fn strict_checks_report_corruption() {
+        let mut tree = AvlTree::with_strict_checks(true);
+        tree.insert(5);
+        tree.insert(3);
+        // the cached size of the left leaf is not on the path of the next insertion
+        let leaf = unsafe { (*tree.root.unwrap().as_ptr()).left.unwrap() };
+        unsafe { (*leaf.as_ptr()).size += 1 };
+        tree.insert(8);
+    }
+
+    #[test]
+    fn enable_self_check() {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let mut tree: AvlTree<u32> = (0..10).collect();
+        let sink = reports.clone();
+        tree.enable_self_check(Box::new(move |violation| {
+            sink.borrow_mut().push(format!("{}", violation))
+        }));
+        tree.insert(10);
+        assert!(reports.take().is_empty());
+
+        // the cached size of the smallest leaf is not on the path of the next removal
+        let mut leaf = tree.root.unwrap();
+        while let Some(left) = unsafe { (*leaf.as_ptr()).left } {
+            leaf = left;
+        }
+        unsafe { (*leaf.as_ptr()).size += 1 };
+        assert!(tree.remove(&10));
+        let reported = reports.take();
+        assert_eq!(1, reported.len());
+        assert!(reported[0].contains("caches size"));
+
+        tree.disable_self_check();
+        tree.insert(10);
+        assert!(reports.take().is_empty());
+        unsafe { (*leaf.as_ptr()).size -= 1 };
+    }
+
+    #[test]
+    fn order_statistics_edge_cases() {
+        let empty = AvlTree::<u32>::new();
+        assert_eq!(None, empty.select(0));
+        assert_eq!(None, empty.select(usize::MAX));
+        assert_eq!(0, empty.rank(&5));
+        assert_eq!(None, empty.checked_rank(&5));
+
+        let tree: AvlTree<u32> = (0..100).map(|value| value * 2).collect();
+        let len = tree.len();
+        assert_eq!(None, tree.select(len));
+        assert_eq!(None, tree.select(usize::MAX));
+        assert_eq!(tree.max(), tree.select(len - 1));
+        assert_eq!(tree.min(), tree.select(0));
+        for (k, value) in tree.iter().enumerate() {
+            assert_eq!(Some(k), tree.checked_rank(value));
+            assert_eq!(k, tree.rank(value));
+        }
+        // absent values are ranked by the number of smaller values, but have no position
+        assert_eq!(None, tree.checked_rank(&7));
+        assert_eq!(4, tree.rank(&7));
+        assert_eq!(None, tree.checked_rank(&1000));
+        assert_eq!(len, tree.rank(&1000));
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn sample() {
         let mut rng = rand::thread_rng();
-        for _ in 0..1000 {
-            let num = rng.gen::<u32>();
-            tree.insert(num);
-            expected.insert(num);
+        assert_eq!(None, AvlTree::<u32>::new().sample(&mut rng));
+
+        let tree: AvlTree<u32> = (0..10).map(|value| value * 3).collect();
+        let mut counts = HashMap::new();
+        for _ in 0..100_000 {
+            *counts.entry(*tree.sample(&mut rng).unwrap()).or_insert(0) += 1;
         }
-        assert!(itertools::all(expected.iter(), |value| tree
-            .contains(value)
-            == expected.contains(value)))
+        // each value is expected 10000 times with a standard deviation below 100
+        assert_eq!(10, counts.len());
+        assert!(counts.values().all(|count| (9500..=10500).contains(count)));
     }
 
     #[test]
-    fn tree_length() {
+    fn pop_min_max_outside_in() {
+        let mut values = (0..11).collect_vec();
+        values.shuffle(&mut rand::thread_rng());
         let mut tree = AvlTree::new();
-        let mut rng = rand::thread_rng();
-        for _ in 0..1000 {
-            tree.insert(rng.gen::<u32>());
+        for value in values {
+            tree.insert(value);
         }
-        assert_eq!(1000, tree.len())
+        let mut outside_in = Vec::new();
+        while let (Some(min), max) = tree.pop_min_max() {
+            assert_eq!(Ok(()), tree.validate());
+            outside_in.push((min, max));
+        }
+        assert_eq!(
+            vec![
+                (0, Some(10)),
+                (1, Some(9)),
+                (2, Some(8)),
+                (3, Some(7)),
+                (4, Some(6)),
+                (5, None)
+            ],
+            outside_in
+        );
+        assert_eq!((None, None), tree.pop_min_max());
     }
 }
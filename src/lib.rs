@@ -0,0 +1,3 @@
+mod avl_tree;
+
+pub use avl_tree::*;
@@ -1,13 +1,13 @@
-use avl_tree::AvlTree;
+use avl_tree::{AvlTree, DotTheme, Violation};
 use clap::{Parser, ValueEnum};
 use graphviz_rust::{cmd::Format, exec_dot};
+use std::cmp::Reverse;
 use std::env;
+use std::fmt::{self, Display};
 use std::io::Write;
 use std::process::ExitCode;
 use std::{fs::File, path};
 
-mod avl_tree;
-
 /// Program to visualize AVL-Trees
 #[derive(Debug, Parser)]
 struct Args {
@@ -23,6 +23,22 @@ struct Args {
     /// Whether to Output the Tree as SVGs or dotfiles
     #[arg(short = 't')]
     filetype: OutputType,
+    /// Color theme of the rendered Trees. Defaults to the plain graphviz style.
+    #[arg(long = "theme")]
+    theme: Option<Theme>,
+    /// Draw nodes that violate the AVL criterion in red.
+    #[arg(long = "show-violations")]
+    show_violations: bool,
+    /// Insert the values like into a plain binary search tree, without rotations, to show
+    /// what an unbalanced tree looks like.
+    #[arg(long = "no-rebalance")]
+    no_rebalance: bool,
+    /// Order in which the values are sorted into the Tree.
+    #[arg(long = "order", value_enum, default_value_t = Order::Asc)]
+    order: Order,
+    /// Validate every generated Tree and report broken invariants on stderr.
+    #[arg(long = "check")]
+    check: bool,
 }
 
 /// The Filetype that should be generated from the trees dotfile.
@@ -33,6 +49,42 @@ pub enum OutputType {
     Pdf,
 }
 
+/// The color theme that should be applied to the trees dotfile.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum Theme {
+    Light,
+    Dark,
+    Print,
+}
+
+/// The order of the values in the tree, i.e. whether smaller values are placed left or right.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Value of a tree built with `--order desc`. It is ordered like [`Reverse`], so the largest
+/// value ends up leftmost in the tree, but displays as the plain value.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Descending(Reverse<i32>);
+
+impl Display for Descending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0 .0)
+    }
+}
+
+impl From<Theme> for DotTheme {
+    fn from(theme: Theme) -> Self {
+        match theme {
+            Theme::Light => DotTheme::Light,
+            Theme::Dark => DotTheme::Dark,
+            Theme::Print => DotTheme::Print,
+        }
+    }
+}
+
 /// Generates outputfiles based on the dotfiles that are passed and writes them to the given Path.
 ///
 /// ## Arguments
@@ -47,10 +99,10 @@ fn generate_files(
     match filetype {
         OutputType::Dotfile => {
             for (index, dotfile) in dotfiles.into_iter().enumerate() {
-                let mut p = path::PathBuf::from(path.clone());
+                let mut p = path.clone();
                 p.extend(&[format!("out-{}", index)]);
                 let mut file = File::create(p)?;
-                file.write_all(&dotfile.as_bytes())?;
+                file.write_all(dotfile.as_bytes())?;
             }
         }
         _ => {
@@ -63,7 +115,7 @@ fn generate_files(
                 match svg {
                     Err(e) => return Err(e),
                     Ok(s) => {
-                        let mut p = path::PathBuf::from(path.clone());
+                        let mut p = path.clone();
                         p.extend(&[format!("out-{}.{}", index, ext)]);
                         let mut file = File::create(p)?;
                         file.write_all(&s)?;
@@ -75,18 +127,78 @@ fn generate_files(
     Ok(())
 }
 
-fn main() -> ExitCode {
-    let args = Args::parse();
+/// Describes a broken invariant of a tree the way `--check` reports it on stderr.
+///
+/// ## Arguments
+/// - `name` The name of the output file of the tree, to tell the reported trees apart.
+/// - `violation` The broken invariant found by [`AvlTree::validate`].
+fn violation_message<V: Display>(name: &str, violation: &Violation<'_, V>) -> String {
+    format!("{}: invalid tree: {}", name, violation)
+}
+
+/// Validates the tree and reports the first broken invariant.
+///
+/// ## Arguments
+/// - `tree` The tree to validate.
+/// - `name` The name of the output file of the tree, to tell the reported trees apart.
+/// - `report` Where to write the broken invariant to, stderr for the CLI.
+///
+/// ## Returns
+/// Whether the tree is valid.
+fn check_tree<V: Ord + Display>(tree: &AvlTree<V>, name: &str, report: &mut impl Write) -> bool {
+    match tree.validate() {
+        Ok(()) => true,
+        Err(violation) => {
+            // a report that cannot be written still leaves the exit code to tell the failure
+            let _ = writeln!(report, "{}", violation_message(name, &violation));
+            false
+        }
+    }
+}
+
+/// Builds the tree from `values` and renders it as requested by `args`. Returns one dotfile
+/// per inserted value if intermediate trees are requested and a single dotfile otherwise,
+/// together with whether every checked tree is valid.
+///
+/// ## Arguments
+/// - `args` The parsed command line arguments.
+/// - `values` The values to put into the tree, in insertion order.
+fn render_trees<V: Ord + Display>(
+    args: &Args,
+    values: impl Iterator<Item = V>,
+) -> (Vec<String>, bool) {
     let mut dotfiles: Vec<String> = Vec::new();
-    if args.intermediates {
-        let mut t = AvlTree::new();
-        for (index, value) in args.values.into_iter().enumerate() {
+    let mut valid = true;
+    let mut render = |t: &AvlTree<V>, index: usize| {
+        if args.check {
+            valid &= check_tree(t, &format!("out-{}", index), &mut std::io::stderr());
+        }
+        t.as_dotfile_styled(args.theme.map(Into::into), args.show_violations)
+            .unwrap_or(String::from(""))
+    };
+    let mut t = AvlTree::new();
+    for (index, value) in values.enumerate() {
+        if args.no_rebalance {
+            t.insert_without_rebalancing(value);
+        } else {
             t.insert(value);
-            dotfiles.insert(index, t.as_dotfile().unwrap_or(String::from("")));
         }
-    } else {
-        let t: AvlTree<i32> = args.values.into_iter().collect();
-        dotfiles.insert(0, t.as_dotfile().unwrap_or(String::from("")));
+        if args.intermediates {
+            dotfiles.insert(index, render(&t, index));
+        }
+    }
+    if !args.intermediates {
+        dotfiles.insert(0, render(&t, 0));
+    }
+    (dotfiles, valid)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let values = args.values.iter().copied();
+    let (dotfiles, valid) = match args.order {
+        Order::Asc => render_trees(&args, values),
+        Order::Desc => render_trees(&args, values.map(|value| Descending(Reverse(value)))),
     };
 
     match generate_files(
@@ -98,5 +210,60 @@ fn main() -> ExitCode {
         Err(_) => return ExitCode::FAILURE,
     }
 
-    ExitCode::SUCCESS
+    if valid {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod main_tests {
+    use super::*;
+
+    #[test]
+    fn check_tree_accepts_valid_trees() {
+        let mut tree = AvlTree::new();
+        let mut report = Vec::new();
+        for value in 0..100 {
+            tree.insert(value);
+            assert!(check_tree(&tree, "out-0", &mut report));
+        }
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn check_tree_reports_invalid_trees() {
+        let mut tree = AvlTree::new();
+        for value in [1, 2, 3] {
+            tree.insert_without_rebalancing(value);
+        }
+        let mut report = Vec::new();
+        assert!(!check_tree(&tree, "out-2", &mut report));
+        assert_eq!(
+            "out-2: invalid tree: node 1 is unbalanced with subtree heights 0 and 2\n",
+            String::from_utf8(report).unwrap()
+        );
+    }
+
+    #[test]
+    fn violation_message() {
+        let violation = Violation::StaleHeight {
+            value: &5,
+            cached: 3,
+            actual: 2,
+        };
+        assert_eq!(
+            "out-2: invalid tree: node 5 caches height 3 but has height 2",
+            super::violation_message("out-2", &violation)
+        );
+        let violation = Violation::Unordered {
+            previous: &7,
+            value: &4,
+        };
+        assert_eq!(
+            "out-0: invalid tree: 4 follows 7 but is not greater",
+            super::violation_message("out-0", &violation)
+        );
+    }
 }
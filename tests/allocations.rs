@@ -0,0 +1,65 @@
+//! Counts heap allocations with a global allocator, which is kept to this test binary so it
+//! does not apply to the other tests.
+
+use avl_tree::{AvlTree, IterScratch};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts the allocations made by the current thread, so tests running in parallel do not
+/// disturb each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations made by `f` on the current thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+#[test]
+fn iter_with_scratch_reuses_the_buffer() {
+    let mut tree: AvlTree<u64> = (0..1000).map(|value| value * 7919 % 1000).collect();
+    let mut scratch = IterScratch::new();
+    assert_eq!(1000, tree.iter_with_scratch(&mut scratch).count());
+
+    let mut sum = 0;
+    let with_scratch = allocations(|| {
+        for _ in 0..100 {
+            sum += tree.iter_with_scratch(&mut scratch).sum::<u64>();
+        }
+    });
+    let without_scratch = allocations(|| {
+        for _ in 0..100 {
+            sum += tree.iter().sum::<u64>();
+        }
+    });
+    assert_eq!(0, with_scratch);
+    assert!(without_scratch >= 100);
+    assert!(sum > 0);
+
+    // a tree of the same height still fits into the buffer after modifications
+    tree.remove(&0);
+    tree.insert(1000);
+    assert_eq!(
+        0,
+        allocations(|| sum += tree.iter_with_scratch(&mut scratch).sum::<u64>())
+    );
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Runs the CLI on the values 1 to 7, which form a perfect tree, and returns the dotfile.
+fn render_dotfile(order: &str) -> String {
+    let output_directory: PathBuf =
+        std::env::temp_dir().join(format!("avl_tree_cli_{}_{}", order, std::process::id()));
+    fs::create_dir_all(&output_directory).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_avl_tree"))
+        .args(["-t", "dotfile", "--order", order, "-o"])
+        .arg(&output_directory)
+        .args(["-v", "4", "2", "6", "1", "3", "5", "7"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let dotfile = fs::read_to_string(output_directory.join("out-0")).unwrap();
+    fs::remove_dir_all(&output_directory).unwrap();
+    dotfile
+}
+
+/// Follows the left children from the root of the dotfile. The edge to the left child of a
+/// node is written before the one to its right child, so in a perfect tree the first edge
+/// leaving a node leads to its left child.
+fn leftmost_node(dotfile: &str) -> String {
+    let lines: Vec<&str> = dotfile.lines().map(str::trim).collect();
+    let mut node = lines[1].to_string();
+    while let Some(edge) = lines
+        .iter()
+        .find(|line| line.starts_with(&format!("{} -> ", node)))
+    {
+        node = edge.rsplit(" -> ").next().unwrap().to_string();
+    }
+    node
+}
+
+#[test]
+fn order_asc_puts_smallest_value_leftmost() {
+    assert_eq!("1", leftmost_node(&render_dotfile("asc")));
+}
+
+#[test]
+fn order_desc_puts_largest_value_leftmost() {
+    assert_eq!("7", leftmost_node(&render_dotfile("desc")));
+}
+
+#[test]
+fn check_accepts_valid_trees() {
+    let output_directory: PathBuf =
+        std::env::temp_dir().join(format!("avl_tree_cli_check_{}", std::process::id()));
+    fs::create_dir_all(&output_directory).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_avl_tree"))
+        .args(["-t", "dotfile", "-i", "--check", "-o"])
+        .arg(&output_directory)
+        .args(["-v", "5", "3", "8", "1", "4", "2"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&output_directory).unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+/// Runs the CLI with `args` writing dotfiles and returns them in the order of their index.
+fn render_dotfiles(name: &str, args: &[&str]) -> Vec<String> {
+    let output_directory: PathBuf =
+        std::env::temp_dir().join(format!("avl_tree_cli_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&output_directory).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_avl_tree"))
+        .args(["-t", "dotfile", "-o"])
+        .arg(&output_directory)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let dotfiles = (0..)
+        .map_while(|index| fs::read_to_string(output_directory.join(format!("out-{}", index))).ok())
+        .collect();
+    fs::remove_dir_all(&output_directory).unwrap();
+    dotfiles
+}
+
+#[test]
+fn final_tree_matches_last_intermediate() {
+    // 1 2 3 4 does not form a perfect tree, so it tells inserting apart from bulk building
+    let values = ["-v", "1", "2", "3", "4"];
+    let last = render_dotfiles("plain", &values);
+    let intermediates = render_dotfiles("intermediates", &[&["-i"], &values[..]].concat());
+    assert_eq!(1, last.len());
+    assert_eq!(4, intermediates.len());
+    assert_eq!(last[0], intermediates[3]);
+    assert!(last[0].contains("2 -> 3") && last[0].contains("3 -> 4"));
+}
+
+#[test]
+fn show_violations_highlights_unbalanced_nodes() {
+    let args = ["--no-rebalance", "--show-violations", "--theme", "dark"];
+    let dotfiles = render_dotfiles("violations", &[&args[..], &["-v", "5", "3", "2"]].concat());
+    let highlighted: Vec<&str> = dotfiles[0]
+        .lines()
+        .filter(|line| line.contains("red"))
+        .collect();
+    assert_eq!(1, highlighted.len());
+    assert!(highlighted[0].trim_start().starts_with("5["));
+    assert!(dotfiles[0].contains("bgcolor=\"#1e1e1e\""));
+
+    // the rotations of a normal build leave nothing to highlight
+    let dotfiles = render_dotfiles("balanced", &["--show-violations", "-v", "5", "3", "2"]);
+    assert!(!dotfiles[0].contains("red"));
+}
+
+#[test]
+fn check_reports_invalid_trees() {
+    let output_directory: PathBuf =
+        std::env::temp_dir().join(format!("avl_tree_cli_check_invalid_{}", std::process::id()));
+    fs::create_dir_all(&output_directory).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_avl_tree"))
+        .args(["-t", "dotfile", "-i", "--check", "--no-rebalance", "-o"])
+        .arg(&output_directory)
+        .args(["-v", "1", "2", "3"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&output_directory).unwrap();
+    assert!(!output.status.success());
+    // the first two trees are still balanced, only the last one is reported
+    assert_eq!(
+        "out-2: invalid tree: node 1 is unbalanced with subtree heights 0 and 2\n",
+        String::from_utf8(output.stderr).unwrap()
+    );
+}
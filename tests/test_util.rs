@@ -0,0 +1,15 @@
+#![cfg(feature = "test-util")]
+
+use avl_tree::{debug_assert_tree, AvlTree};
+
+#[test]
+fn debug_assert_tree_is_exported() {
+    let mut tree: AvlTree<u32> = AvlTree::new();
+    for value in (0..200).rev() {
+        tree.insert(value);
+        debug_assert_tree(&tree);
+    }
+    while tree.pop_min().is_some() {
+        debug_assert_tree(&tree);
+    }
+}